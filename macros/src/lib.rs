@@ -0,0 +1,54 @@
+//! Derive macros for bevy_skynet.
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, DeriveInput, LitInt, LitStr};
+
+/// Implements StableMessage, declaring the MessageKey of a message type with
+/// "#[stable_message(name = "game::PlayerInput")]" or "#[stable_message(id = 42)]".
+#[proc_macro_derive(StableMessage, attributes(stable_message))]
+pub fn derive_stable_message(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match stable_message(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn stable_message(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let mut key = None;
+    for attr in input.attrs.iter().filter(|attr| attr.path().is_ident("stable_message")) {
+        attr.parse_nested_meta(|meta| {
+            if key.is_some() {
+                return Err(meta.error("the key of a StableMessage is declared more than once"));
+            }
+
+            if meta.path.is_ident("name") {
+                let name = meta.value()?.parse::<LitStr>()?;
+                key = Some(quote!(::bevy_skynet::context::MessageKey::Name(#name)));
+                Ok(())
+            } else if meta.path.is_ident("id") {
+                let id = meta.value()?.parse::<LitInt>()?.base10_parse::<u64>()?;
+                key = Some(quote!(::bevy_skynet::context::MessageKey::Id(#id)));
+                Ok(())
+            } else {
+                Err(meta.error("expected \"name\" or \"id\""))
+            }
+        })?;
+    }
+
+    let Some(key) = key else {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "StableMessage requires #[stable_message(name = \"...\")] or #[stable_message(id = ...)]",
+        ));
+    };
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::bevy_skynet::context::StableMessage for #ident #ty_generics #where_clause {
+            const KEY: ::bevy_skynet::context::MessageKey = #key;
+        }
+    })
+}
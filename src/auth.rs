@@ -0,0 +1,371 @@
+//! Authentication of peers joining a lobby or server.
+//!
+//! When "general.require_auth" is enabled, clients send a ticket issued by the
+//! backend to the host as soon as they join. Until the host has validated that
+//! ticket, every other message from the client is dropped before it reaches the
+//! MessageRegistry. Clients that fail validation, or do not send a ticket within
+//! "general.auth_timeout" seconds, have their session closed.
+//!
+//! Only the host validates tickets; other members trust the host to have done so.
+//! This is why "general.require_auth" forces the Star topology: clients only
+//! hear from other members through the host, which drops the packets of
+//! members it has not authenticated before relaying them.
+
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use bevy::prelude::*;
+use bevy::log;
+use serde::{Deserialize, Serialize};
+use crate::backends::{Backend, IBackend, IBackendEvents, IsLobbyHost, OnLobbyChange, OnLobbyJoin, UserId};
+use crate::context::{message_id, NetContext};
+use crate::params::{NetReceiver, NetSender};
+
+/// Internal message carrying an authentication ticket from a client to the host.
+#[derive(Serialize, Deserialize, TypePath)]
+pub struct AuthTicket {
+    #[serde(with = "serde_bytes")]
+    pub ticket: Vec<u8>,
+}
+
+/// The host finished validating the ticket of a client.
+#[derive(Event, Debug, Clone)]
+pub struct OnAuthResult {
+    /// The user that was authenticated.
+    pub user: UserId,
+
+    /// Whether the ticket was valid.
+    pub result: Result<(), AuthError>,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum AuthError {
+    /// The ticket was malformed or not issued for us.
+    InvalidTicket,
+
+    /// A ticket was already submitted by this user.
+    DuplicateRequest,
+
+    /// The ticket was issued by an incompatible version of the backend.
+    InvalidVersion,
+
+    /// The ticket was issued for another game.
+    GameMismatch,
+
+    /// The ticket has expired.
+    ExpiredTicket,
+
+    /// The ticket was cancelled or has already been used.
+    Cancelled,
+
+    /// The user is not connected to the backend's services.
+    NotConnected,
+
+    /// The user does not own the game.
+    NoLicense,
+
+    /// The user is banned from the game.
+    Banned,
+
+    /// The user logged in somewhere else.
+    LoggedInElsewhere,
+
+    /// The user did not send a ticket in time.
+    TimedOut,
+
+    /// An unknown error code was returned.
+    Unknown,
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use AuthError::*;
+        f.write_str(match *self {
+            InvalidTicket => "Invalid Ticket",
+            DuplicateRequest => "Duplicate Request",
+            InvalidVersion => "Invalid Version",
+            GameMismatch => "Game Mismatch",
+            ExpiredTicket => "Expired Ticket",
+            Cancelled => "Ticket Cancelled",
+            NotConnected => "Not Connected",
+            NoLicense => "No License",
+            Banned => "Banned",
+            LoggedInElsewhere => "Logged In Elsewhere",
+            TimedOut => "Timed Out",
+            Unknown => "Unknown Error",
+        })
+    }
+}
+
+#[cfg(any(feature = "steam", feature = "steam_server"))]
+impl From<steamworks::AuthSessionError> for AuthError {
+    fn from(value: steamworks::AuthSessionError) -> Self {
+        use steamworks::AuthSessionError::*;
+        match value {
+            InvalidTicket => Self::InvalidTicket,
+            DuplicateRequest => Self::DuplicateRequest,
+            InvalidVersion => Self::InvalidVersion,
+            GameMismatch => Self::GameMismatch,
+            ExpiredTicket => Self::ExpiredTicket,
+        }
+    }
+}
+
+#[cfg(any(feature = "steam", feature = "steam_server"))]
+impl From<steamworks::AuthSessionValidateError> for AuthError {
+    fn from(value: steamworks::AuthSessionValidateError) -> Self {
+        use steamworks::AuthSessionValidateError::*;
+        match value {
+            UserNotConnectedToSteam => Self::NotConnected,
+            NoLicenseOrExpired => Self::NoLicense,
+            VACBanned => Self::Banned,
+            LoggedInElseWhere => Self::LoggedInElsewhere,
+            VACCheckTimedOut => Self::Unknown,
+            AuthTicketCancelled => Self::Cancelled,
+            AuthTicketInvalidAlreadyUsed => Self::Cancelled,
+            AuthTicketInvalid => Self::InvalidTicket,
+            PublisherIssuedBan => Self::Banned,
+        }
+    }
+}
+
+/// Authentication state of the peers connected to the host.
+#[derive(Resource, Default)]
+pub struct AuthSessions {
+    /// Whether peers must authenticate before their messages are delivered.
+    required: bool,
+
+    /// Peers whose tickets were validated.
+    authenticated: HashSet<UserId>,
+
+    /// Peers whose tickets are being validated by the backend.
+    validating: HashSet<UserId>,
+
+    /// Peers that have not sent a ticket yet, and when they time out.
+    pending: HashMap<UserId, Duration>,
+
+    /// The message ID of the AuthTicket.
+    ticket_id: u64,
+}
+
+impl AuthSessions {
+    pub fn new(required: bool) -> Self {
+        Self {
+            required,
+            ticket_id: message_id(AuthTicket::type_path()),
+            ..default()
+        }
+    }
+
+    /// Whether peers must authenticate before their messages are delivered.
+    pub fn is_required(&self) -> bool {
+        self.required
+    }
+
+    /// Whether the ticket of the user has been validated.
+    pub fn is_authenticated(&self, user: UserId) -> bool {
+        self.authenticated.contains(&user)
+    }
+
+    /// Whether a message from the user may be delivered to the MessageRegistry.
+    /// Authentication tickets are always accepted.
+    pub(crate) fn accepts(&self, user: UserId, msg_id: u64) -> bool {
+        !self.required || msg_id == self.ticket_id || self.authenticated.contains(&user)
+    }
+
+    /// Expect a ticket from a member that joined before "deadline",
+    /// unless its ticket already arrived.
+    fn expect(&mut self, user: UserId, deadline: Duration) {
+        if !self.authenticated.contains(&user) && !self.validating.contains(&user) {
+            self.pending.entry(user).or_insert(deadline);
+        }
+    }
+
+    /// Start validating the ticket of the user.
+    /// Returns "false" if the user already sent a ticket.
+    fn begin(&mut self, user: UserId) -> bool {
+        if self.authenticated.contains(&user) || self.validating.contains(&user) {
+            return false;
+        }
+        self.pending.remove(&user);
+        self.validating.insert(user);
+        true
+    }
+
+    fn authenticate(&mut self, user: UserId) {
+        self.validating.remove(&user);
+        self.pending.remove(&user);
+        self.authenticated.insert(user);
+    }
+
+    /// Forget the members that did not send a ticket before their deadline, returning them.
+    fn expire(&mut self, now: Duration) -> Vec<UserId> {
+        let expired = self.pending.iter()
+            .filter(|&(_, expires)| *expires <= now)
+            .map(|(user, _)| *user)
+            .collect::<Vec<_>>();
+        for user in &expired {
+            self.remove(*user);
+        }
+        expired
+    }
+
+    fn remove(&mut self, user: UserId) {
+        self.authenticated.remove(&user);
+        self.validating.remove(&user);
+        self.pending.remove(&user);
+    }
+}
+
+/// Send an authentication ticket to the host after joining a lobby or server.
+pub fn send_auth_ticket(
+    context: Res<NetContext>,
+    backend: Res<Backend>,
+    mut on_lobby_join: EventReader<OnLobbyJoin>,
+    mut sender: NetSender<AuthTicket>,
+) {
+    for _ in on_lobby_join.read() {
+        if !context.config.general.require_auth {
+            continue;
+        }
+
+        let is_host = backend.current_lobby().is_none_or(|curr| curr.is_host);
+        if let (false, Some(host)) = (is_host, backend.host_id()) {
+            log::debug!("Sending authentication ticket to host '{:?}'", host);
+            let ticket = backend.auth_ticket(host);
+            sender.send(host, &AuthTicket { ticket });
+        }
+    }
+}
+
+/// Begin validating the tickets clients sent to the host.
+pub fn validate_auth_tickets(
+    is_host: Res<State<IsLobbyHost>>,
+    backend: Res<Backend>,
+    mut sessions: ResMut<AuthSessions>,
+    mut tickets: NetReceiver<AuthTicket>,
+    mut on_auth_result: EventWriter<OnAuthResult>,
+) {
+    while let Some(msg) = tickets.recv() {
+        let user = msg.sender;
+        if !sessions.required || *is_host.get() != IsLobbyHost::True {
+            continue;
+        }
+
+        if !sessions.begin(user) {
+            log::warn!("UserID '{:?}' sent an authentication ticket, but already has one.", user);
+            continue;
+        }
+
+        if let Err(e) = backend.begin_auth(user, &msg.payload.ticket) {
+            log::warn!("Rejected authentication ticket from UserID '{:?}' (reason: '{e}')", user);
+            sessions.remove(user);
+            backend.reject_session(user);
+            on_auth_result.write(OnAuthResult { user, result: Err(e) });
+        }
+    }
+}
+
+/// Apply validation results, time out peers that never sent a ticket,
+/// and forget peers that leave.
+pub fn apply_auth_results(
+    context: Res<NetContext>,
+    is_host: Res<State<IsLobbyHost>>,
+    time: Res<Time<Real>>,
+    mut backend: ResMut<Backend>,
+    mut sessions: ResMut<AuthSessions>,
+    mut on_lobby_change: EventReader<OnLobbyChange>,
+    mut on_auth_result: EventWriter<OnAuthResult>,
+) {
+    let now = time.elapsed();
+    let results = backend.events().read_auth_results().collect::<Vec<_>>();
+    for ev in results {
+        match ev.result {
+            Ok(()) => {
+                log::debug!("Authenticated UserID '{:?}'", ev.user);
+                sessions.authenticate(ev.user);
+            }
+            Err(e) => {
+                log::warn!("Authentication of UserID '{:?}' failed (reason: '{e}')", ev.user);
+                sessions.remove(ev.user);
+                backend.end_auth(ev.user);
+                backend.reject_session(ev.user);
+            }
+        }
+        on_auth_result.write(ev);
+    }
+
+    for ev in on_lobby_change.read() {
+        match ev {
+            OnLobbyChange::Joined(user) => {
+                // the ticket may arrive before the lobby reports the member as joined.
+                if sessions.required && *is_host.get() == IsLobbyHost::True {
+                    let timeout = Duration::from_secs_f32(context.config.general.auth_timeout);
+                    sessions.expect(*user, now + timeout);
+                }
+            }
+            OnLobbyChange::Exited(user)
+            | OnLobbyChange::Kicked { target: user, .. }
+            | OnLobbyChange::Banned { target: user, .. } => {
+                sessions.remove(*user);
+                backend.end_auth(*user);
+            }
+        }
+    }
+
+    for user in sessions.expire(now) {
+        log::warn!("UserID '{:?}' did not send an authentication ticket in time.", user);
+        backend.reject_session(user);
+        on_auth_result.write(OnAuthResult { user, result: Err(AuthError::TimedOut) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(id: u64) -> UserId {
+        UserId::from_raw(id)
+    }
+
+    const OTHER: u64 = message_id("Other");
+
+    #[test]
+    fn only_tickets_are_accepted_until_authenticated() {
+        let a = user(1);
+        let mut sessions = AuthSessions::new(true);
+        let ticket = message_id(AuthTicket::type_path());
+        assert!(sessions.accepts(a, ticket));
+        assert!(!sessions.accepts(a, OTHER));
+
+        assert!(sessions.begin(a));
+        assert!(!sessions.accepts(a, OTHER));
+        // a second ticket is rejected while the first is validated, and once it was.
+        assert!(!sessions.begin(a));
+        sessions.authenticate(a);
+        assert!(!sessions.begin(a));
+        assert!(sessions.is_authenticated(a));
+        assert!(sessions.accepts(a, OTHER));
+
+        // leaving forgets the authentication.
+        sessions.remove(a);
+        assert!(!sessions.accepts(a, OTHER));
+        assert!(AuthSessions::new(false).accepts(a, OTHER));
+    }
+
+    #[test]
+    fn members_time_out_without_a_ticket() {
+        let (a, b, c) = (user(1), user(2), user(3));
+        let mut sessions = AuthSessions::new(true);
+        sessions.expect(a, Duration::from_secs(5));
+        sessions.expect(b, Duration::from_secs(5));
+
+        // c's ticket arrived before the lobby reported it as joined.
+        assert!(sessions.begin(c));
+        sessions.expect(c, Duration::from_secs(5));
+
+        assert!(sessions.begin(b));
+        assert!(sessions.expire(Duration::from_secs(4)).is_empty());
+        assert_eq!(sessions.expire(Duration::from_secs(5)), [a]);
+        assert!(sessions.expire(Duration::from_secs(10)).is_empty());
+    }
+}
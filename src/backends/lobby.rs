@@ -71,6 +71,9 @@ pub enum LobbyVisibility {
 #[derive(Event, Debug)]
 pub struct OnLobbyExit {
     pub id: LobbyId,
+
+    /// Whether the connection to the host was lost, rather than the lobby being left.
+    pub dropped: bool,
 }
 
 /// Response returned when the user attempts to join a lobby.
@@ -196,7 +199,7 @@ impl std::fmt::Display for LobbyErrorKind {
     }
 }
 
-#[cfg(feature = "steam")]
+#[cfg(any(feature = "steam", feature = "steam_server"))]
 impl TryFrom<steamworks::ChatRoomEnterResponse> for LobbyErrorKind {
     type Error = ();
     fn try_from(value: steamworks::ChatRoomEnterResponse) -> Result<Self, ()> {
@@ -218,7 +221,7 @@ impl TryFrom<steamworks::ChatRoomEnterResponse> for LobbyErrorKind {
     }
 }
 
-#[cfg(feature = "steam")]
+#[cfg(any(feature = "steam", feature = "steam_server"))]
 impl TryFrom<steamworks::LobbyCreated> for LobbyErrorKind {
     type Error = ();
     fn try_from(value: steamworks::LobbyCreated) -> Result<Self, Self::Error> {
@@ -8,25 +8,52 @@
 //!  - A "Friend" struct that implements "IFriend"
 //!  - A "LobbyId" struct that implements Clone + Eq + PartialEq + Hash + Debug
 //!  - A "UserId" struct
+//! 
+//! Client backends are selected with the "steam" feature, server backends
+//! with the "steam_server" feature. If both are enabled, the server backend is used.
 
 use bevy::prelude::*;
 use bevy::log;
 
-#[cfg(feature = "steam")]
+#[cfg(any(feature = "steam", feature = "steam_server"))]
 pub mod steam;
-#[cfg(feature = "steam")]
+#[cfg(all(feature = "steam", not(feature = "steam_server")))]
 pub use steam::*;
 
+#[cfg(feature = "steam_server")]
+pub mod steam_server;
+#[cfg(feature = "steam_server")]
+pub use steam_server::*;
+
 pub mod lobby;
 pub use lobby::*;
 
-use crate::context::NetContext;
-use crate::SkynetConfig;
+use crate::auth::{AuthError, AuthSessions, OnAuthResult};
+use crate::context::{Arrival, Delivery, NetContext};
+use crate::error::NetError;
+use crate::handshake::CompactIds;
+use crate::limits::{LimitAction, RateLimiter};
+use crate::header;
+use crate::outbox::{self, Outbox};
+use crate::relay::{self, Route, Router, Topology};
+use crate::secure::SecureChannels;
+use crate::{NetMode, ServerConfig, SkynetConfig};
 
 /// Trait for ensuring uniformity across multiple backends. 
 pub trait IBackend: Resource {
+    /// The BackendEvents struct exported by the backend module.
+    type Events: IBackendEvents;
+
     fn from_config(config: &SkynetConfig) -> Self;
 
+    /// Whether packets sent over this backend should be encrypted by the
+    /// SecureChannels, according to the backend's section of the config.
+    fn secure_channel(config: &SkynetConfig) -> bool;
+
+    /// The pre-shared key authenticating the SecureChannels' key exchange,
+    /// or an empty string if it is unauthenticated.
+    fn secure_key(config: &SkynetConfig) -> &str;
+
     /// The ID of the user. 
     fn user_id(&self) -> UserId;
 
@@ -42,6 +69,9 @@ pub trait IBackend: Resource {
     /// Get a handle to the user's friend list.
     fn friends(&self) -> Vec<Friend>;
 
+    /// Whether some user is on the user's friend list.
+    fn is_friend(&self, user: UserId) -> bool;
+
     /// Get the current state of the lobby.
     fn lobby_state(&self) -> LobbyState;
 
@@ -70,6 +100,35 @@ pub trait IBackend: Resource {
     /// this function will return "false".
     fn join_lobby(&self, lobby: LobbyId) -> bool;
 
+    /// Connect to a dedicated server as a client. The server is treated as a lobby
+    /// the user is in, and as the host of that lobby. Dispatches an OnLobbyJoin event.
+    /// 
+    /// If the user is already connected to a lobby or is joining/creating one,
+    /// this function will return "false".
+    fn join_server(&self, server: UserId) -> bool;
+
+    /// Begin listening for clients as a dedicated server. Clients are reported
+    /// as lobby members, and the server is always the host. 
+    /// 
+    /// Returns "false" if the backend cannot run as a server.
+    fn listen(&self, config: &ServerConfig) -> bool;
+
+    /// The ID of the host of the current lobby or server, if the user is in one.
+    fn host_id(&self) -> Option<UserId>;
+
+    /// Convert a UserId to a u64 that can be sent to other users.
+    fn encode_user_id(&self, user: UserId) -> u64;
+
+    /// Convert a u64 created with "encode_user_id" back to a UserId.
+    fn decode_user_id(&self, raw: u64) -> UserId;
+
+    /// Join the lobby or server the connection was last lost to again.
+    /// Dispatches an OnLobbyJoin event, like "join_lobby".
+    ///
+    /// Returns "false" if no connection was lost, or the user is already
+    /// connected to a lobby or is joining/creating one.
+    fn reconnect(&self) -> bool;
+
     /// Send a lobby leave request for the current lobby. Dispatches an OnLobbyExit event.
     /// 
     /// If the user is not already connected to a lobby, "false" is returned.
@@ -92,6 +151,28 @@ pub trait IBackend: Resource {
     /// Not intended for end-user use.
     fn broadcast_packet(&self, data: &[u8]);
 
+    /// Accept a pending session request, allowing packets from the user to be received.
+    /// Not intended for end-user use.
+    fn accept_session(&self, user: UserId);
+
+    /// Reject a pending session request, or close an open session with the user.
+    /// Not intended for end-user use.
+    fn reject_session(&self, user: UserId);
+
+    /// Issue a ticket the host can use to authenticate this user.
+    /// Backends without platform tickets may issue a signed token instead.
+    /// Not intended for end-user use.
+    fn auth_ticket(&self, host: UserId) -> Vec<u8>;
+
+    /// Begin validating a ticket sent by some user. The result is reported
+    /// through the BackendEvents, unless the ticket is rejected immediately.
+    /// Not intended for end-user use.
+    fn begin_auth(&self, user: UserId, ticket: &[u8]) -> Result<(), AuthError>;
+
+    /// Stop tracking the authentication session of some user.
+    /// Not intended for end-user use.
+    fn end_auth(&self, user: UserId);
+
     /// Receive the next available packet. 
     /// Returns the id of the sender and the number of bytes written.
     /// Always sends in the highest reliability mode available. 
@@ -99,9 +180,13 @@ pub trait IBackend: Resource {
     /// Not intended for end-user use. 
     fn recv_packet(&self, buf: &mut [u8]) -> Option<(UserId, usize)>;
 
+    /// The size of the next packet "recv_packet" would return, if one is available.
+    /// Not intended for end-user use. 
+    fn next_packet_size(&self) -> Option<usize>;
+
     /// Get a reader over the Backend Events
     /// Not intended for end-user use. 
-    fn events(&mut self) -> &mut BackendEvents;
+    fn events(&mut self) -> &mut Self::Events;
 
     /// Ran once per frame. 
     /// Not intended for end-user use. 
@@ -133,12 +218,31 @@ pub trait IBackendEvents {
 
     /// Read lobby connection errors
     fn read_lobby_connect_errors(&mut self) -> impl Iterator<Item=LobbyConnectError>;
+
+    /// Read the users that are requesting to open a session
+    fn read_session_requests(&mut self) -> impl Iterator<Item=UserId>;
+
+    /// Read the results of ticket validation
+    fn read_auth_results(&mut self) -> impl Iterator<Item=OnAuthResult>;
+}
+
+/// Start listening for clients when running as a dedicated server.
+pub fn start_server(
+    context: Res<NetContext>,
+    backend: Res<Backend>,
+) {
+    if backend.listen(&context.config.server) {
+        log::info!("Listening for clients on port '{}'.", context.config.server.port);
+    } else {
+        log::error!("NetMode is Server, but the backend failed to start listening for clients.");
+    }
 }
 
 /// Convert steamwork events to bevy events
 pub fn read_backend_events(
+    mode: Res<State<NetMode>>,
     curr_state: Res<State<LobbyState>>,
-    curr_lobby: Option<ResMut<CurrentLobby>>,
+    mut curr_lobby: Option<ResMut<CurrentLobby>>,
     curr_is_host: Res<State<IsLobbyHost>>,
     mut next_is_host: ResMut<NextState<IsLobbyHost>>,
     mut next_state: ResMut<NextState<LobbyState>>,
@@ -169,14 +273,16 @@ pub fn read_backend_events(
         }
     } 
 
-    let is_host = if let Some(mut curr_lobby) = curr_lobby {
-        // update current lobby members
+    // update current lobby members
+    if let Some(curr_lobby) = curr_lobby.as_mut() {
         curr_lobby.others = backend.lobby_members();
-        if curr_lobby.is_host {
-            IsLobbyHost::True
-        } else {
-            IsLobbyHost::False
-        }
+    }
+
+    let is_host = if *mode.get() == NetMode::Server {
+        // a dedicated server is always the authority.
+        IsLobbyHost::True
+    } else if curr_lobby.is_some_and(|curr_lobby| curr_lobby.is_host) {
+        IsLobbyHost::True
     } else {
         IsLobbyHost::False
     };
@@ -187,22 +293,200 @@ pub fn read_backend_events(
 }
 
 /// Receive available packets and send them to the ECS for receipt. 
+#[allow(clippy::too_many_arguments)]
 pub fn recv_incoming_packets(
     context: Res<NetContext>,
     backend: Res<Backend>,
+    secure: Res<SecureChannels>,
+    router: Res<Router>,
+    outbox: Res<Outbox>,
+    auth: Res<AuthSessions>,
+    compact: Res<CompactIds>,
+    is_host: Res<State<IsLobbyHost>>,
+    time: Res<Time<Real>>,
+    mut limiter: ResMut<RateLimiter>,
+    mut on_error: EventWriter<NetError>,
     mut buf: Local<Vec<u8>>,
 ) {
-    if buf.len() < 1200 {
-        buf.resize(1200, 0);
+    let mtu = outbox.mtu();
+    if buf.len() < mtu {
+        buf.resize(mtu, 0);
     }
-    
+
     let registry = context.messages.clone();
-    while let Some((user_id, len)) = backend.recv_packet(&mut buf) {
-        if len <= 8 {
-            log::warn!("P2P Backend Received a packet that was too small and was discarded (len: '{}')", buf.len());
-        } else {
-            let msg_id = u64::from_be_bytes([buf[0], buf[1], buf[2], buf[3], buf[4], buf[5], buf[6], buf[7]]);
-            registry.send(msg_id, &buf[8..], user_id);
+    let is_host = *is_host.get() == IsLobbyHost::True;
+    let now = time.elapsed();
+    let deliver_local = |user_id: UserId, packet: &[u8], delivery: Delivery| {
+        let Some(header) = header::read(packet).filter(|h| packet.len() > h.len) else {
+            log::warn!("Discarded a packet from UserID '{:?}' with a malformed header.", user_id);
+            return;
+        };
+
+        let compressed = header.flags & header::COMPRESSED != 0;
+        let payload = &packet[header.len..];
+        let arrival = Arrival { sender: user_id, received: now, size: payload.len(), delivery };
+        if let Err(error) = registry.send(header.id, compressed, payload, arrival, &backend) {
+            context.report(error);
+        }
+    };
+
+    let deliver = |user_id: UserId, packet: &[u8]| {
+        let Some(header) = header::read(packet) else { return };
+        let msg_id = relay::inner_msg_id(packet).unwrap_or(header.id);
+        let accepted = match is_host {
+            true => auth.accepts(user_id, msg_id),
+            // clients only hear from other members through the host, which authenticated them.
+            false => !auth.is_required() || backend.host_id() == Some(user_id),
+        };
+        if !accepted {
+            log::debug!("Dropped a packet from UserID '{:?}' because they are not authenticated.", user_id);
+            return;
+        }
+
+        match router.route(&backend, header, packet) {
+            None => log::warn!("Discarded a malformed relayed packet from UserID '{:?}'", user_id),
+            Some(Route::Direct(packet)) => deliver_local(user_id, packet, Delivery::Direct),
+            Some(Route::Forward { origin, packet }) => {
+                if backend.host_id() != Some(user_id) {
+                    log::warn!("Discarded a relayed packet from UserID '{:?}', which is not the host.", user_id);
+                    return;
+                }
+                deliver_local(origin, packet, Delivery::Relayed);
+            }
+            Some(Route::Relay { target, packet }) => {
+                if !is_host || router.topology() != Topology::Star {
+                    log::warn!("Discarded a packet UserID '{:?}' asked us to relay, because we are not a relay.", user_id);
+                    return;
+                }
+
+                let message = registry.get(msg_id);
+                if let Some(message) = message.as_ref().filter(|m| !m.options.authority.permits(user_id, target, &backend)) {
+                    context.report(NetError::Unauthorized { user: user_id, message: message.name });
+                    return;
+                }
+
+                let priority = message.map_or(1.0, |m| m.options.priority);
+
+                // only relay to current members, so the host cannot be used to reach arbitrary users.
+                let members = backend.lobby_members();
+                match target {
+                    Some(target) if target == backend.user_id() => deliver_local(user_id, packet, Delivery::Direct),
+                    Some(target) if !members.contains(&target) => {
+                        log::warn!("Discarded a packet UserID '{:?}' asked us to relay to UserID '{:?}', which is not a lobby member.", user_id, target);
+                    }
+                    Some(target) => router.forward(&backend, &outbox, user_id, target, packet, priority),
+                    None => {
+                        for member in members.into_iter().filter(|member| *member != user_id) {
+                            router.forward(&backend, &outbox, user_id, member, packet, priority);
+                        }
+                        deliver_local(user_id, packet, Delivery::Direct);
+                    }
+                }
+            }
+        }
+    };
+
+    limiter.tick(now);
+    let mut budget = limiter.frame_budget();
+
+    // Deliver packets held from throttled peers first, so they stay in order.
+    for user_id in limiter.throttled() {
+        while budget > 0 {
+            let Some((len, msg_id)) = limiter.front(user_id).map(|p| (p.len(), relay::inner_msg_id(p))) else { break };
+            let message = msg_id.and_then(|id| registry.get(id));
+            if limiter.check(user_id, len, message.as_deref()).is_err() {
+                break;
+            }
+
+            budget -= 1;
+            if let Some(packet) = limiter.pop_front(user_id) {
+                deliver(user_id, &packet);
+            }
+        }
+    }
+
+    while budget > 0 {
+        // packets larger than the MTU are sent alone, and must not be truncated.
+        if let Some(size) = backend.next_packet_size().filter(|size| *size > buf.len()) {
+            buf.resize(size, 0);
+        }
+
+        let Some((user_id, len)) = backend.recv_packet(&mut buf) else { break };
+        budget -= 1;
+        if limiter.is_kicked(user_id) {
+            continue;
+        }
+
+        let Some(datagram) = secure.open(&backend, user_id, &buf[..len], now) else {
+            continue;
+        };
+
+        let Some(packets) = outbox::unbatch(&datagram) else {
+            log::warn!("Discarded a malformed batch of packets from UserID '{:?}'", user_id);
+            continue;
+        };
+
+        for packet in packets {
+            if limiter.is_kicked(user_id) {
+                break;
+            }
+
+            // resolved with the indices the peer agreed on, which may predate a change of our registry.
+            let ids = compact.ids_of(user_id);
+            if header::is_compact(packet) && ids.is_none() {
+                log::debug!("Discarded a packet with a compact header from UserID '{:?}', whose registry differs.", user_id);
+                continue;
+            }
+
+            let Some(packet) = header::expand(packet, ids.unwrap_or_default()) else {
+                log::warn!("Discarded a packet from UserID '{:?}' with a malformed header.", user_id);
+                continue;
+            };
+            let packet = &*packet;
+
+            if header::read(packet).is_none_or(|h| packet.len() <= h.len) {
+                log::warn!("P2P Backend Received a packet that was too small and was discarded (len: '{}')", packet.len());
+                continue;
+            }
+
+            // With a Star topology the host relays everyone's packets, so its budget would not hold.
+            if router.is_relay(&backend, user_id) {
+                deliver(user_id, packet);
+                continue;
+            }
+
+            let throttle = limiter.action() == LimitAction::Throttle;
+            if throttle && limiter.is_throttled(user_id) {
+                if !limiter.defer(user_id, packet.to_vec()) {
+                    log::warn!("Dropped a packet from UserID '{:?}' because its backlog is full.", user_id);
+                }
+                continue;
+            }
+
+            let message = relay::inner_msg_id(packet).and_then(|id| registry.get(id));
+            let Err(error) = limiter.check(user_id, packet.len(), message.as_deref()) else {
+                deliver(user_id, packet);
+                continue;
+            };
+
+            let report = limiter.report(user_id);
+            match limiter.action() {
+                LimitAction::Drop => {}
+                LimitAction::Throttle => {
+                    if !limiter.defer(user_id, packet.to_vec()) {
+                        log::warn!("Dropped a packet from UserID '{:?}' because its backlog is full.", user_id);
+                    }
+                }
+                LimitAction::Kick => {
+                    backend.reject_session(user_id);
+                    limiter.kick(user_id);
+                }
+            }
+
+            if report {
+                log::warn!("{error}");
+                on_error.write(error);
+            }
         }
     }
-}
\ No newline at end of file
+}
@@ -3,10 +3,11 @@ use std::sync::Arc;
 
 use bevy::ecs::resource::Resource;
 use bevy::utils::default;
-use parking_lot::RwLock;
-use steamworks::{CallbackHandle, ChatEntryType, FriendFlags, GameLobbyJoinRequested, LobbyChatMsg, LobbyChatUpdate, LobbyCreated, LobbyEnter, LobbyType, P2PSessionRequest, SResult, SendType};
+use parking_lot::{Mutex, RwLock};
+use steamworks::{AuthTicket, CallbackHandle, ChatEntryType, FriendFlags, GameLobbyJoinRequested, LobbyChatMsg, LobbyChatUpdate, LobbyCreated, LobbyEnter, LobbyType, P2PSessionConnectFail, P2PSessionRequest, SResult, SendType, ValidateAuthTicketResponse};
 use crate::prelude::{OnLobbyExit, OnLobbyJoin};
 use crate::util::Receiver;
+use crate::auth::{AuthError, OnAuthResult};
 use crate::backends::{ChatKind, CurrentLobby, IBackendEvents, LobbyErrorKind, LobbyState, LobbyVisibility, LobbyConnectError, OnLobbyMessage};
 use bevy::log;
 
@@ -30,12 +31,17 @@ pub struct Backend {
     /// Event receivers
     events: BackendEvents,
 
+    /// Authentication tickets issued for the current lobby, cancelled when it is exited.
+    tickets: Mutex<Vec<AuthTicket>>,
+
     lobby_create_cb: CallbackHandle,
     lobby_enter_cb: CallbackHandle,
     lobby_msg_cb: CallbackHandle,
     lobby_change_cb: CallbackHandle,
     lobby_accept_cb: CallbackHandle,
     lobby_autojoin_cb: CallbackHandle,
+    session_fail_cb: CallbackHandle,
+    auth_validate_cb: CallbackHandle,
 }
 
 impl Backend {
@@ -145,11 +151,14 @@ impl Backend {
             }
         });
 
-        // auto-accept all connection requests.
-        let client2 = client.clone();
+        // connection requests are accepted or rejected according to the SessionPolicy.
+        let tx = events.on_session_request.tx();
         let lobby_accept_cb = client.register_callback(move |ev: P2PSessionRequest| {
-            log::debug!("Accepted P2P Session Request from UserID: '{:?}'", ev.remote);
-            client2.networking().accept_p2p_session(ev.remote);
+            log::debug!("P2PSessionRequest event received from Steamworks API (UserID: '{:?}')", ev.remote);
+
+            if tx.try_send(ev.remote).is_err() {
+                log::error!("[E554] A P2PSessionRequest was received, but its event receiver is full.");
+            }
         });
 
         // Auto accept attempts by the user to join a lobby by clicking "Join Game" or "Accept Invite"
@@ -166,7 +175,7 @@ impl Backend {
                 // send lobby exit event if already in lobby
                 if lobby.state == LobbyState::InLobby {
                     client2.matchmaking().leave_lobby(lobby.curr.id);
-                    if let Err(_) = exit_tx.try_send(OnLobbyExit { id: lobby.curr.id }) {
+                    if let Err(_) = exit_tx.try_send(OnLobbyExit { id: lobby.curr.id, dropped: false }) {
                         log::error!("[E555] A LobbyExit occurred, but its event receiver was full.")
                     }
                 }
@@ -182,26 +191,75 @@ impl Backend {
             }
         });
 
+        // losing the connection to the host drops us from the lobby, so it can be rejoined.
+        let lobby2 = lobby.clone();
+        let client2 = client.clone();
+        let exit_tx = events.on_lobby_exit.tx();
+        let session_fail_cb = client.register_callback(move |ev: P2PSessionConnectFail| {
+            log::debug!("P2PSessionConnectFail event received from Steamworks API (UserID: '{:?}')", ev.remote);
+
+            let mut lobby = lobby2.write();
+            let Some(curr) = lobby.get_if_in_lobby() else { return };
+            let host = lobby.server.unwrap_or_else(|| client2.matchmaking().lobby_owner(curr.id));
+            if curr.is_host || ev.remote != host {
+                return;
+            }
+
+            let server = lobby.server.take();
+            if server.is_none() {
+                client2.matchmaking().leave_lobby(curr.id);
+            }
+            lobby.dropped = Some((curr.id, server));
+            lobby.state = LobbyState::None;
+            if exit_tx.try_send(OnLobbyExit { id: curr.id, dropped: true }).is_err() {
+                log::error!("[E552] A LobbyExit occurred, but its event receiver was full.");
+            }
+        });
+
+        // results of validating tickets sent to us as the host.
+        let tx = events.on_auth_result.tx();
+        let auth_validate_cb = client.register_callback(move |ev: ValidateAuthTicketResponse| {
+            log::debug!("ValidateAuthTicketResponse event received from Steamworks API");
+
+            let result = ev.response.map_err(AuthError::from);
+            if tx.try_send(OnAuthResult { user: ev.steam_id, result }).is_err() {
+                log::error!("[E551] A ValidateAuthTicketResponse was received, but its event receiver is full.");
+            }
+        });
+
         Self {
             raw: client,
             lobby,
             members: Vec::new(),
             events,
+            tickets: Mutex::new(Vec::new()),
             lobby_create_cb,
             lobby_enter_cb,
             lobby_msg_cb,
             lobby_change_cb,
             lobby_accept_cb,
             lobby_autojoin_cb,
+            session_fail_cb,
+            auth_validate_cb,
         }
     }
 }
 
 impl super::IBackend for Backend {
+    type Events = BackendEvents;
+
     fn from_config(config: &crate::SkynetConfig) -> Self {
         Self::new(config.steamworks.app_id, config.general.channel_size as usize)
     }
 
+    fn secure_channel(config: &crate::SkynetConfig) -> bool {
+        config.steamworks.secure_channel
+    }
+
+    fn secure_key(config: &crate::SkynetConfig) -> &str {
+        &config.steamworks.secure_key
+    }
+
     fn user_id(&self) -> UserId {
         self.raw.user().steam_id()
     }
@@ -227,6 +285,12 @@ impl super::IBackend for Backend {
             .collect::<Vec<_>>()
     }
 
+    fn is_friend(&self, user: UserId) -> bool {
+        self.raw.friends()
+            .get_friend(user)
+            .has_friend(FriendFlags::IMMEDIATE)
+    }
+
     fn lobby_state(&self) -> LobbyState {
         self.lobby.read().state.clone()
     }
@@ -296,11 +360,72 @@ impl super::IBackend for Backend {
         }
     }
 
+    fn join_server(&self, server: UserId) -> bool {
+        let data = CurrentLobby {
+            id: LobbyId::from_raw(server.raw()),
+            is_host: false,
+            ..default()
+        };
+
+        let mut lobby = self.lobby.write();
+        if lobby.set_joining_if_none(data).is_some() {
+            // P2P sessions are opened on the first packet, so there is nothing to wait for.
+            lobby.state = LobbyState::InLobby;
+            lobby.server = Some(server);
+            lobby.curr.invite_code = base62::encode(server.raw());
+            lobby.curr.others = vec![server];
+            self.events.on_lobby_join.send(OnLobbyJoin { id: lobby.curr.id });
+            true
+        } else {
+            false
+        }
+    }
+
+    fn reconnect(&self) -> bool {
+        let dropped = self.lobby.read().dropped;
+        match dropped {
+            Some((_, Some(server))) => self.join_server(server),
+            Some((lobby, None)) => self.join_lobby(lobby),
+            None => false,
+        }
+    }
+
+    fn listen(&self, _: &crate::ServerConfig) -> bool {
+        log::error!("The Steam client backend cannot run as a dedicated server, enable the 'steam_server' feature instead.");
+        false
+    }
+
+    fn host_id(&self) -> Option<UserId> {
+        let lobby = self.lobby.read();
+        match (lobby.get_if_in_lobby(), lobby.server) {
+            (Some(_), Some(server)) => Some(server),
+            (Some(curr), None) => Some(self.raw.matchmaking().lobby_owner(curr.id)),
+            (None, _) => None,
+        }
+    }
+
+    fn encode_user_id(&self, user: UserId) -> u64 {
+        user.raw()
+    }
+
+    fn decode_user_id(&self, raw: u64) -> UserId {
+        UserId::from_raw(raw)
+    }
+
     fn exit_lobby(&self) -> bool {
-        if let Some(curr) = self.lobby.read().get_if_in_lobby() {
-            self.events.on_lobby_exit.send(OnLobbyExit { id: curr.id });
-            self.raw.matchmaking().leave_lobby(curr.id);
-            self.lobby.write().state = LobbyState::None;
+        let curr = self.lobby.read().get_if_in_lobby();
+        if let Some(curr) = curr {
+            self.events.on_lobby_exit.send(OnLobbyExit { id: curr.id, dropped: false });
+            for ticket in self.tickets.lock().drain(..) {
+                self.raw.user().cancel_authentication_ticket(ticket);
+            }
+            let mut lobby = self.lobby.write();
+            match lobby.server.take() {
+                Some(server) => self.raw.networking().close_p2p_session(server),
+                None => self.raw.matchmaking().leave_lobby(curr.id),
+            }
+            lobby.state = LobbyState::None;
+            lobby.dropped = None;
             true
         } else {
             false
@@ -308,7 +433,9 @@ impl super::IBackend for Backend {
     }
 
     fn lobby_members(&self) -> Vec<UserId> {
-        if let Some(curr) = self.lobby.read().get_if_in_lobby() {
+        if let Some(server) = self.lobby.read().server {
+            vec![server]
+        } else if let Some(curr) = self.lobby.read().get_if_in_lobby() {
             let mut members = self.raw.matchmaking().lobby_members(curr.id);
             let user = self.user_id();
             if let Some(i) = members.iter().position(|id| user == *id) {
@@ -321,7 +448,9 @@ impl super::IBackend for Backend {
     }
 
     fn send_lobby_message(&self, msg: &str) {
-        if let Some(curr) = self.lobby.read().get_if_in_lobby() {
+        if self.lobby.read().server.is_some() {
+            log::warn!("Lobby chat messages are not available while connected to a dedicated server.");
+        } else if let Some(curr) = self.lobby.read().get_if_in_lobby() {
             if let Err(e) = self.raw.matchmaking().send_lobby_chat_message(curr.id, msg.as_bytes()) {
                 log::error!("Attempted to send a lobby chat message, but steam returned an error: '{e}'");
             }
@@ -338,10 +467,38 @@ impl super::IBackend for Backend {
         }
     }
 
+    fn accept_session(&self, user: UserId) {
+        self.raw.networking().accept_p2p_session(user);
+    }
+
+    fn reject_session(&self, user: UserId) {
+        self.raw.networking().close_p2p_session(user);
+    }
+
+    fn auth_ticket(&self, host: UserId) -> Vec<u8> {
+        let (handle, ticket) = self.raw.user().authentication_session_ticket_with_steam_id(host);
+        self.tickets.lock().push(handle);
+        ticket
+    }
+
+    fn begin_auth(&self, user: UserId, ticket: &[u8]) -> Result<(), AuthError> {
+        self.raw.user()
+            .begin_authentication_session(user, ticket)
+            .map_err(AuthError::from)
+    }
+
+    fn end_auth(&self, user: UserId) {
+        self.raw.user().end_authentication_session(user);
+    }
+
     fn recv_packet(&self, buf: &mut [u8]) -> Option<(UserId, usize)> {
         self.raw.networking().read_p2p_packet(buf)
     }
 
+    fn next_packet_size(&self) -> Option<usize> {
+        self.raw.networking().is_p2p_packet_available()
+    }
+
     fn events(&mut self) -> &mut BackendEvents {
         &mut self.events
     }
@@ -372,6 +529,12 @@ pub struct BackendEvents {
 
     /// Errors that can occur when joining or creating. 
     on_lobby_error: Receiver<LobbyConnectError>,
+
+    /// Users attempting to open a P2P session.
+    on_session_request: Receiver<UserId>,
+
+    /// Results of validating authentication tickets.
+    on_auth_result: Receiver<OnAuthResult>,
 }
 
 impl BackendEvents {
@@ -382,6 +545,8 @@ impl BackendEvents {
             on_lobby_msg: Receiver::new(size),
             on_lobby_change: Receiver::new(size),
             on_lobby_error: Receiver::new(size),
+            on_session_request: Receiver::new(size),
+            on_auth_result: Receiver::new(size),
         }
     }
 }
@@ -422,6 +587,14 @@ impl IBackendEvents for BackendEvents {
     fn read_lobby_connect_errors(&mut self) -> impl Iterator<Item=LobbyConnectError> {
         self.on_lobby_error.iter()
     }
+
+    fn read_session_requests(&mut self) -> impl Iterator<Item=UserId> {
+        self.on_session_request.iter()
+    }
+
+    fn read_auth_results(&mut self) -> impl Iterator<Item=OnAuthResult> {
+        self.on_auth_result.iter()
+    }
 }
 
 fn log_cb<T>(res: SResult<T>) {
@@ -451,13 +624,21 @@ fn convert_chat_entry_type(entry: ChatEntryType) -> ChatKind {
 struct LobbyData {
     state: LobbyState,
     curr: CurrentLobby,
+
+    /// The dedicated server the user is connected to, if not in a Steam lobby.
+    server: Option<UserId>,
+
+    /// The lobby, and the server if any, the connection was last lost to.
+    dropped: Option<(LobbyId, Option<UserId>)>,
 }
 
 impl Default for LobbyData {
     fn default() -> Self {
         Self {
             state: LobbyState::None,
-            curr: CurrentLobby::default()
+            curr: CurrentLobby::default(),
+            server: None,
+            dropped: None,
         }
     }
 }
@@ -0,0 +1,488 @@
+//! Dedicated server backend built on the Steamworks game server API.
+//!
+//! The server logs on anonymously, registers itself with the server browser
+//! when it begins listening, and treats every client with an accepted P2P
+//! session as a member of its lobby. Logging on completes asynchronously, and
+//! the server only has a SteamID afterwards, so OnLobbyJoin is sent once it is
+//! both listening and logged on.
+
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+
+use bevy::ecs::resource::Resource;
+use parking_lot::RwLock;
+use steamworks::{CallbackHandle, P2PSessionConnectFail, P2PSessionRequest, ServerMode, SteamServerConnectFailure, SteamServersConnected, ValidateAuthTicketResponse};
+use crate::prelude::{OnLobbyExit, OnLobbyJoin};
+use crate::util::Receiver;
+use crate::auth::{AuthError, OnAuthResult};
+use crate::backends::{CurrentLobby, IBackendEvents, LobbyState, LobbyVisibility, LobbyConnectError, LobbyErrorKind, OnLobbyChange, OnLobbyMessage};
+use crate::{ServerConfig, SkynetConfig};
+use bevy::log;
+
+mod networking;
+use networking::ServerNetworking;
+
+pub use super::steam::{Friend, LobbyId, UserId};
+
+#[derive(Resource)]
+pub struct Backend {
+    /// Raw handle to the steamworks game server.
+    raw: steamworks::Server,
+
+    /// Handle used to run callbacks for the game server.
+    client: steamworks::Client,
+
+    /// The game server's P2P networking interface.
+    net: Arc<ServerNetworking>,
+
+    /// Listening state and connected clients.
+    data: Arc<RwLock<ServerData>>,
+
+    /// Event receivers
+    events: BackendEvents,
+
+    _callbacks: Vec<CallbackHandle>,
+}
+
+impl Backend {
+    /// Initialize the Steamworks game server backend and log on anonymously.
+    pub fn new(config: &SkynetConfig) -> Self {
+        let steam = &config.steamworks;
+        let mode = if steam.vac_secure {
+            ServerMode::AuthenticationAndSecure
+        } else {
+            ServerMode::Authentication
+        };
+
+        let (server, client) = match steamworks::Server::init(
+            Ipv4Addr::UNSPECIFIED,
+            config.server.port,
+            steam.query_port,
+            mode,
+            &steam.server_version,
+        ) {
+            Ok(pair) => pair,
+            Err(e) => {
+                panic!("An error occured while initializing Steamworks Server backend: '{e}'.");
+            }
+        };
+
+        server.set_product(&steam.product);
+        server.set_game_description(&steam.game_description);
+        server.set_dedicated_server(true);
+        server.log_on_anonymous();
+
+        let net = Arc::new(ServerNetworking::new());
+        let data = Arc::new(RwLock::new(ServerData {
+            map_name: steam.map_name.clone(),
+            game_tags: steam.game_tags.clone(),
+            ..ServerData::default()
+        }));
+        let events = BackendEvents::new(config.general.channel_size as usize);
+
+        // the server joins its own lobby once it is listening and logged on.
+        let data2 = data.clone();
+        let server2 = server.clone();
+        let tx = events.on_lobby_join.tx();
+        let connected_cb = server.register_callback(move |_: SteamServersConnected| {
+            log::debug!("SteamServersConnected event received from Steamworks API");
+
+            let mut data = data2.write();
+            data.logged_on = true;
+            if data.listening && !data.joined {
+                data.joined = true;
+                if tx.try_send(OnLobbyJoin { id: LobbyId::from_raw(server2.steam_id().raw()) }).is_err() {
+                    log::error!("[E550] The server logged on, but its event receiver is full.");
+                }
+            }
+        });
+
+        let tx = events.on_lobby_error.tx();
+        let connect_fail_cb = server.register_callback(move |ev: SteamServerConnectFailure| {
+            log::error!("The server failed to log on to Steam (error: '{}', retrying: '{}')", ev.reason, ev.still_retrying);
+
+            let error = LobbyConnectError { id: LobbyId::from_raw(0), kind: LobbyErrorKind::Offline };
+            if tx.try_send(error).is_err() {
+                log::error!("[E549] The server failed to log on, but its event receiver is full.");
+            }
+        });
+
+        // connection requests are accepted or rejected according to the SessionPolicy.
+        let tx = events.on_session_request.tx();
+        let session_request_cb = server.register_callback(move |ev: P2PSessionRequest| {
+            log::debug!("P2PSessionRequest event received from Steamworks API (UserID: '{:?}')", ev.remote);
+
+            if tx.try_send(ev.remote).is_err() {
+                log::error!("[E554] A P2PSessionRequest was received, but its event receiver is full.");
+            }
+        });
+
+        // clients whose connection failed are no longer members.
+        let data2 = data.clone();
+        let server2 = server.clone();
+        let tx = events.on_lobby_change.tx();
+        let session_fail_cb = server.register_callback(move |ev: P2PSessionConnectFail| {
+            log::debug!("P2PSessionConnectFail event received from Steamworks API (UserID: '{:?}', error: '{}')", ev.remote, ev.error);
+
+            if data2.write().remove(ev.remote) {
+                server2.end_authentication_session(ev.remote);
+                if tx.try_send(OnLobbyChange::Exited(ev.remote)).is_err() {
+                    log::error!("[E553] A client disconnected, but its event receiver is full.");
+                }
+            }
+        });
+
+        // results of validating the tickets sent by joining clients.
+        let tx = events.on_auth_result.tx();
+        let validate_cb = server.register_callback(move |ev: ValidateAuthTicketResponse| {
+            log::debug!("ValidateAuthTicketResponse event received from Steamworks API");
+
+            let result = ev.response.map_err(AuthError::from);
+            if tx.try_send(OnAuthResult { user: ev.steam_id, result }).is_err() {
+                log::error!("[E551] A ValidateAuthTicketResponse was received, but its event receiver is full.");
+            }
+        });
+
+        Self {
+            raw: server,
+            client,
+            net,
+            data,
+            events,
+            _callbacks: vec![connected_cb, connect_fail_cb, session_request_cb, session_fail_cb, validate_cb],
+        }
+    }
+
+    fn lobby_id(&self) -> LobbyId {
+        LobbyId::from_raw(self.raw.steam_id().raw())
+    }
+}
+
+impl super::IBackend for Backend {
+    type Events = BackendEvents;
+
+    fn from_config(config: &SkynetConfig) -> Self {
+        Self::new(config)
+    }
+
+    fn secure_channel(config: &crate::SkynetConfig) -> bool {
+        config.steamworks.secure_channel
+    }
+
+    fn secure_key(config: &crate::SkynetConfig) -> &str {
+        &config.steamworks.secure_key
+    }
+
+    fn user_id(&self) -> UserId {
+        self.raw.steam_id()
+    }
+
+    fn user_name(&self) -> String {
+        self.data.read().name.clone()
+    }
+
+    fn name_of(&self, user: UserId) -> String {
+        // Game servers do not have access to the persona names of users.
+        user.raw().to_string()
+    }
+
+    fn preferred_ui_language(&self) -> Option<String> {
+        None
+    }
+
+    fn friends(&self) -> Vec<Friend> {
+        Vec::new()
+    }
+
+    fn is_friend(&self, _: UserId) -> bool {
+        false
+    }
+
+    fn lobby_state(&self) -> LobbyState {
+        let data = self.data.read();
+        match (data.listening, data.joined) {
+            (_, true) => LobbyState::InLobby,
+            (true, false) => LobbyState::Joining,
+            (false, false) => LobbyState::None,
+        }
+    }
+
+    fn current_lobby(&self) -> Option<CurrentLobby> {
+        let data = self.data.read();
+        if data.joined {
+            let id = self.lobby_id();
+            Some(CurrentLobby {
+                id,
+                vis: LobbyVisibility::Anyone,
+                is_host: true,
+                max_members: data.max_clients,
+                invite_code: base62::encode(id.raw()),
+                others: data.clients.clone(),
+            })
+        } else {
+            None
+        }
+    }
+
+    fn create_lobby(&self, _: LobbyVisibility, _: u32) -> bool {
+        log::warn!("A dedicated server cannot create lobbies, it listens for clients instead.");
+        false
+    }
+
+    fn encode_lobby_id(&self) -> Option<String> {
+        if self.data.read().joined {
+            Some(base62::encode(self.lobby_id().raw()))
+        } else {
+            None
+        }
+    }
+
+    fn decode_lobby_id(&self, id: String) -> LobbyId {
+        match base62::decode(id.as_bytes()) {
+            Err(e) => {
+                log::error!("Failed to decode lobby ID with error: '{e}'");
+                LobbyId::from_raw(0)
+            },
+            Ok(n) => LobbyId::from_raw(n as u64),
+        }
+    }
+
+    fn join_lobby(&self, _: LobbyId) -> bool {
+        log::warn!("A dedicated server cannot join lobbies.");
+        false
+    }
+
+    fn join_server(&self, _: UserId) -> bool {
+        log::warn!("A dedicated server cannot join other servers.");
+        false
+    }
+
+    fn reconnect(&self) -> bool {
+        false
+    }
+
+    fn listen(&self, config: &ServerConfig) -> bool {
+        let mut data = self.data.write();
+        if data.listening {
+            return false;
+        }
+
+        self.raw.set_server_name(&config.name);
+        self.raw.set_max_players(config.max_clients as i32);
+        self.raw.set_map_name(&data.map_name);
+        self.raw.set_game_tags(&data.game_tags);
+        // register with the server browser
+        self.raw.set_advertise_server_active(true);
+
+        data.listening = true;
+        data.name = config.name.clone();
+        data.max_clients = config.max_clients;
+
+        // otherwise sent once the server logs on.
+        if data.logged_on {
+            data.joined = true;
+            self.events.on_lobby_join.send(OnLobbyJoin { id: self.lobby_id() });
+        }
+        true
+    }
+
+    fn host_id(&self) -> Option<UserId> {
+        if self.data.read().joined {
+            Some(self.user_id())
+        } else {
+            None
+        }
+    }
+
+    fn encode_user_id(&self, user: UserId) -> u64 {
+        user.raw()
+    }
+
+    fn decode_user_id(&self, raw: u64) -> UserId {
+        UserId::from_raw(raw)
+    }
+
+    fn exit_lobby(&self) -> bool {
+        let mut data = self.data.write();
+        if data.listening {
+            for client in data.clients.drain(..) {
+                self.raw.end_authentication_session(client);
+                self.net.close_p2p_session(client);
+            }
+            self.raw.set_advertise_server_active(false);
+            data.listening = false;
+            if std::mem::take(&mut data.joined) {
+                self.events.on_lobby_exit.send(OnLobbyExit { id: self.lobby_id(), dropped: false });
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    fn lobby_members(&self) -> Vec<UserId> {
+        self.data.read().clients.clone()
+    }
+
+    fn send_lobby_message(&self, _: &str) {
+        log::warn!("Lobby chat messages are not available on a dedicated server.");
+    }
+
+    fn accept_session(&self, user: UserId) {
+        let mut data = self.data.write();
+        if !data.listening {
+            log::warn!("Rejected a session request from UserID '{:?}' because the server is not listening.", user);
+            self.net.close_p2p_session(user);
+        } else {
+            self.net.accept_p2p_session(user);
+            if !data.clients.contains(&user) {
+                data.clients.push(user);
+                self.events.on_lobby_change.send(OnLobbyChange::Joined(user));
+            }
+        }
+    }
+
+    fn reject_session(&self, user: UserId) {
+        self.net.close_p2p_session(user);
+        if self.data.write().remove(user) {
+            self.raw.end_authentication_session(user);
+            self.events.on_lobby_change.send(OnLobbyChange::Exited(user));
+        }
+    }
+
+    fn send_packet(&self, to: UserId, data: &[u8]) {
+        self.net.send_p2p_packet(to, data);
+    }
+
+    fn broadcast_packet(&self, data: &[u8]) {
+        for client in &self.data.read().clients {
+            self.send_packet(*client, data);
+        }
+    }
+
+    fn auth_ticket(&self, host: UserId) -> Vec<u8> {
+        let (_, ticket) = self.raw.authentication_session_ticket_with_steam_id(host);
+        ticket
+    }
+
+    fn begin_auth(&self, user: UserId, ticket: &[u8]) -> Result<(), AuthError> {
+        self.raw
+            .begin_authentication_session(user, ticket)
+            .map_err(AuthError::from)
+    }
+
+    fn end_auth(&self, user: UserId) {
+        self.raw.end_authentication_session(user);
+    }
+
+    fn recv_packet(&self, buf: &mut [u8]) -> Option<(UserId, usize)> {
+        self.net.read_p2p_packet(buf)
+    }
+
+    fn next_packet_size(&self) -> Option<usize> {
+        self.net.is_p2p_packet_available()
+    }
+
+    fn events(&mut self) -> &mut BackendEvents {
+        &mut self.events
+    }
+
+    fn tick(&mut self) {
+        self.client.run_callbacks();
+    }
+}
+
+pub struct BackendEvents {
+    /// Occurs when the server is listening and logged on.
+    on_lobby_join: Receiver<OnLobbyJoin>,
+
+    /// Occurs when the server stops listening.
+    on_lobby_exit: Receiver<OnLobbyExit>,
+
+    /// Game servers have no lobby chat, so this never receives.
+    on_lobby_msg: Receiver<OnLobbyMessage>,
+
+    /// Occurs when a client connects, disconnects, or is kicked.
+    on_lobby_change: Receiver<OnLobbyChange>,
+
+    /// Occurs when the server fails to log on to Steam.
+    on_lobby_error: Receiver<LobbyConnectError>,
+
+    /// Clients attempting to open a P2P session.
+    on_session_request: Receiver<UserId>,
+
+    /// Results of validating the tickets of joining clients.
+    on_auth_result: Receiver<OnAuthResult>,
+}
+
+impl BackendEvents {
+    fn new(size: usize) -> Self {
+        Self {
+            on_lobby_join: Receiver::new(size),
+            on_lobby_exit: Receiver::new(size),
+            on_lobby_msg: Receiver::new(size),
+            on_lobby_change: Receiver::new(size),
+            on_lobby_error: Receiver::new(size),
+            on_session_request: Receiver::new(size),
+            on_auth_result: Receiver::new(size),
+        }
+    }
+}
+
+impl IBackendEvents for BackendEvents {
+    fn read_lobby_join(&mut self) -> impl Iterator<Item=OnLobbyJoin> {
+        self.on_lobby_join.iter()
+    }
+
+    fn read_lobby_exit(&mut self) -> impl Iterator<Item=OnLobbyExit> {
+        self.on_lobby_exit.iter()
+    }
+
+    fn read_lobby_msg(&mut self) -> impl Iterator<Item=OnLobbyMessage> {
+        self.on_lobby_msg.iter()
+    }
+
+    fn read_lobby_change(&mut self) -> impl Iterator<Item=OnLobbyChange> {
+        self.on_lobby_change.iter()
+    }
+
+    fn read_lobby_connect_errors(&mut self) -> impl Iterator<Item=LobbyConnectError> {
+        self.on_lobby_error.iter()
+    }
+
+    fn read_session_requests(&mut self) -> impl Iterator<Item=UserId> {
+        self.on_session_request.iter()
+    }
+
+    fn read_auth_results(&mut self) -> impl Iterator<Item=OnAuthResult> {
+        self.on_auth_result.iter()
+    }
+}
+
+#[derive(Default)]
+struct ServerData {
+    listening: bool,
+
+    /// Whether the server logged on to Steam, and has a SteamID.
+    logged_on: bool,
+
+    /// Whether OnLobbyJoin was sent since the server began listening.
+    joined: bool,
+    name: String,
+    max_clients: u32,
+    map_name: String,
+    game_tags: String,
+    clients: Vec<UserId>,
+}
+
+impl ServerData {
+    /// Remove a client, returning whether it was connected.
+    fn remove(&mut self, user: UserId) -> bool {
+        if let Some(i) = self.clients.iter().position(|id| *id == user) {
+            self.clients.remove(i);
+            true
+        } else {
+            false
+        }
+    }
+}
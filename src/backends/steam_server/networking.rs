@@ -0,0 +1,77 @@
+//! The steamworks crate only exposes the client's ISteamNetworking interface,
+//! but game servers must send and receive P2P packets through the game server's
+//! own interface, so we call into it directly.
+
+use steamworks::{sys, SteamId};
+
+pub(crate) struct ServerNetworking {
+    net: *mut sys::ISteamNetworking,
+}
+
+// The interface is a handle into the Steam API, which is thread-safe.
+unsafe impl Send for ServerNetworking {}
+unsafe impl Sync for ServerNetworking {}
+
+impl ServerNetworking {
+    /// Must only be called after steamworks::Server::init succeeded.
+    pub(crate) fn new() -> Self {
+        let net = unsafe { sys::SteamAPI_SteamGameServerNetworking_v006() };
+        assert!(!net.is_null(), "The Steam game server networking interface is not available.");
+        Self { net }
+    }
+
+    pub(crate) fn accept_p2p_session(&self, user: SteamId) {
+        unsafe {
+            sys::SteamAPI_ISteamNetworking_AcceptP2PSessionWithUser(self.net, user.raw());
+        }
+    }
+
+    pub(crate) fn close_p2p_session(&self, user: SteamId) {
+        unsafe {
+            sys::SteamAPI_ISteamNetworking_CloseP2PSessionWithUser(self.net, user.raw());
+        }
+    }
+
+    pub(crate) fn send_p2p_packet(&self, to: SteamId, data: &[u8]) -> bool {
+        unsafe {
+            sys::SteamAPI_ISteamNetworking_SendP2PPacket(
+                self.net,
+                to.raw(),
+                data.as_ptr().cast(),
+                data.len() as _,
+                sys::EP2PSend::k_EP2PSendReliable,
+                0,
+            )
+        }
+    }
+
+    pub(crate) fn is_p2p_packet_available(&self) -> Option<usize> {
+        unsafe {
+            let mut size = 0;
+            if sys::SteamAPI_ISteamNetworking_IsP2PPacketAvailable(self.net, &mut size, 0) {
+                Some(size as usize)
+            } else {
+                None
+            }
+        }
+    }
+
+    pub(crate) fn read_p2p_packet(&self, buf: &mut [u8]) -> Option<(SteamId, usize)> {
+        unsafe {
+            let mut size = 0;
+            let mut remote = 0u64;
+            if sys::SteamAPI_ISteamNetworking_ReadP2PPacket(
+                self.net,
+                buf.as_mut_ptr().cast(),
+                buf.len() as _,
+                &mut size,
+                &mut remote as *mut _ as *mut _,
+                0,
+            ) {
+                Some((SteamId::from_raw(remote), size as usize))
+            } else {
+                None
+            }
+        }
+    }
+}
@@ -1,35 +1,93 @@
-use tokio::sync::mpsc;
-use std::{io, marker::PhantomData, sync::Arc};
+use std::{collections::VecDeque, io, marker::PhantomData, sync::{Arc, atomic::{AtomicUsize, Ordering}}};
+use parking_lot::Mutex;
 use serde::de::DeserializeOwned;
 use bevy::prelude::*;
-use crate::{backends::UserId, context::{Message, MessageType}};
+use crate::context::{Arrival, Message, MessageType, Overflow};
 use bevy::log;
 
 type CborError = ciborium::de::Error<io::Error>;
 
+/// Received messages of a type, waiting to be read.
+pub(crate) struct Inbox<T> {
+    queue: Mutex<VecDeque<Message<T>>>,
+    capacity: usize,
+    overflow: Overflow,
+
+    /// The number of messages dropped with Overflow::DropOldest.
+    dropped: AtomicUsize,
+}
+
+impl<T: TypePath> Inbox<T> {
+    pub(crate) fn new(capacity: usize, overflow: Overflow) -> Self {
+        Self {
+            queue: Mutex::new(VecDeque::new()),
+            capacity: capacity.max(1),
+            overflow,
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    fn push(&self, message: Message<T>) {
+        let mut queue = self.queue.lock();
+        match self.overflow {
+            Overflow::KeepLatest => queue.clear(),
+            _ if queue.len() < self.capacity => {}
+            Overflow::DropNewest => {
+                log::error!("The message receiver channel for message '{}' is full.", T::type_path());
+                return;
+            }
+            Overflow::DropOldest => {
+                // warn every time the number of dropped messages doubles, as with Overflow::Unbounded.
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                if dropped.is_power_of_two() {
+                    log::warn!("The message receiver channel for message '{}' is full, '{}' of the oldest messages were dropped.", T::type_path(), dropped);
+                }
+                queue.pop_front();
+            }
+            Overflow::Unbounded => {
+                // warn every time the backlog doubles, so a growing backlog does not flood the log.
+                let len = queue.len();
+                if len.is_multiple_of(self.capacity) && (len / self.capacity).is_power_of_two() {
+                    log::warn!("The message receiver channel for message '{}' holds '{}' unread messages.", T::type_path(), len);
+                }
+            }
+        }
+        queue.push_back(message);
+    }
+
+    pub(crate) fn pop(&self) -> Option<Message<T>> {
+        self.queue.lock().pop_front()
+    }
+}
+
 #[derive(Resource)]
 pub struct IncomingRx<T> {
-    pub(crate) rx: mpsc::Receiver<Message<T>>,
+    pub(crate) inbox: Arc<Inbox<T>>,
 }
 
 pub struct IncomingTx<T> {
-    pub(crate) tx: mpsc::Sender<Message<T>>,
+    pub(crate) inbox: Arc<Inbox<T>>,
 }
 
 pub(crate) trait DynamicTx: Send + Sync {
-    fn send(&self, payload: &[u8], sender: UserId) -> Result<(), CborError>;
+    fn send(&self, payload: &[u8], arrival: Arrival, seq: u64) -> Result<(), CborError>;
 }
 
 impl<T> DynamicTx for IncomingTx<T>
 where
     T: DeserializeOwned + Send + Sync + TypePath
 {
-    fn send(&self, payload: &[u8], sender: UserId) -> Result<(), CborError> {
+    fn send(&self, payload: &[u8], arrival: Arrival, seq: u64) -> Result<(), CborError> {
         let cursor = io::Cursor::new(payload);
         let payload: T = ciborium::from_reader(cursor)?;
-        if let Err(_) = self.tx.try_send(Message { sender, payload }) {
-            log::error!("The message receiver channel for message '{}' is full.", T::type_path())
-        }
+        self.inbox.push(Message {
+            sender: arrival.sender,
+            payload,
+            seq,
+            received: arrival.received,
+            size: arrival.size,
+            delivery: arrival.delivery,
+        });
         Ok(())
     }
 }
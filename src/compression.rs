@@ -0,0 +1,108 @@
+//! Optional LZ4 compression of message payloads.
+//!
+//! Compression is selected per message with "MessageOptions::with_compression".
+//! Compressed payloads are prefixed with their decompressed size and marked with
+//! the COMPRESSED flag in the packet header. Payloads are only sent compressed
+//! when that makes them smaller.
+
+use serde::Deserialize;
+
+/// When the payload of a message is compressed.
+#[derive(Deserialize, Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub enum Compression {
+    /// Never compress the payload.
+    #[default]
+    Never,
+
+    /// Always compress the payload.
+    Always,
+
+    /// Compress payloads larger than this many bytes.
+    Above(usize),
+}
+
+impl Compression {
+    /// Whether a payload of the size should be compressed.
+    pub fn applies(&self, len: usize) -> bool {
+        match *self {
+            Compression::Never => false,
+            Compression::Always => true,
+            Compression::Above(threshold) => len > threshold,
+        }
+    }
+}
+
+/// Compress a payload. Returns "None" if compression would not make it smaller.
+pub(crate) fn compress(payload: &[u8]) -> Option<Vec<u8>> {
+    let compressed = lz4_flex::compress_prepend_size(payload);
+    (compressed.len() < payload.len()).then_some(compressed)
+}
+
+/// Decompress a payload, refusing payloads that would decompress to more than "max" bytes.
+/// A "max" of zero is unlimited.
+pub(crate) fn decompress(payload: &[u8], max: usize) -> Option<Vec<u8>> {
+    let size = u32::from_le_bytes(payload.get(..4)?.try_into().ok()?) as usize;
+    if max != 0 && size > max {
+        return None;
+    }
+    // lz4_flex accepts data that decompresses to less than the prefixed size.
+    lz4_flex::decompress_size_prepended(payload).ok().filter(|data| data.len() == size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thresholds() {
+        assert!(!Compression::Never.applies(usize::MAX));
+        assert!(Compression::Always.applies(0));
+        assert!(!Compression::Above(100).applies(100));
+        assert!(Compression::Above(100).applies(101));
+    }
+
+    #[test]
+    fn round_trip() {
+        let payload = b"abcdefgh".repeat(128);
+        let compressed = compress(&payload).unwrap();
+        assert!(compressed.len() < payload.len());
+        assert_eq!(decompress(&compressed, 0).unwrap(), payload);
+        assert_eq!(decompress(&compressed, payload.len()).unwrap(), payload);
+    }
+
+    #[test]
+    fn incompressible_payloads_are_sent_as_is() {
+        assert!(compress(b"").is_none());
+        assert!(compress(b"abc").is_none());
+
+        let noise = (0..256u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8).collect::<Vec<_>>();
+        assert!(compress(&noise).is_none());
+    }
+
+    #[test]
+    fn oversized_payloads_are_refused() {
+        let payload = vec![0; 4096];
+        let compressed = compress(&payload).unwrap();
+        assert!(decompress(&compressed, payload.len() - 1).is_none());
+    }
+
+    #[test]
+    fn corrupt_payloads_are_rejected() {
+        let payload = b"abcdefgh".repeat(128);
+        let compressed = compress(&payload).unwrap();
+
+        assert!(decompress(&[], 0).is_none());
+        assert!(decompress(&compressed[..3], 0).is_none());
+        assert!(decompress(&compressed[..compressed.len() - 1], 0).is_none());
+
+        // a size prefix that does not match the data.
+        let mut wrong_size = compressed.clone();
+        wrong_size[..4].copy_from_slice(&(payload.len() as u32 + 1).to_le_bytes());
+        assert!(decompress(&wrong_size, 0).is_none());
+
+        // garbage after the size prefix.
+        let mut garbage = compressed[..4].to_vec();
+        garbage.extend_from_slice(&[0xff; 32]);
+        assert!(decompress(&garbage, 0).is_none());
+    }
+}
@@ -1,63 +1,460 @@
 
 use bevy::prelude::*;
-use std::sync::Arc;
-use parking_lot::RwLock;
+use std::{marker::PhantomData, sync::Arc};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use parking_lot::{Mutex, RwLock};
 use std::collections::BTreeMap;
-use crate::{backends::UserId, comms::DynamicTx, SkynetConfig};
+use xxhash_rust::const_xxh64::xxh64;
+use serde::de::DeserializeOwned;
+use crate::{backends::{Backend, IBackend, UserId}, compression::{self, Compression}, comms::{DynamicTx, Inbox, IncomingRx, IncomingTx, OutgoingTx}, error::{NetError, RegistryError}, outbox::BATCH_ID, relay::{FORWARD_ID, RELAY_ID}, util::Receiver, SkynetConfig};
 use bevy::log;
 
+/// Compute the ID of a message from its name.
+pub(crate) const fn message_id(name: &str) -> u64 {
+    const SEED: u64 = 0x9e3779b185ebca87;
+    xxh64(name.as_bytes(), SEED)
+}
+
 #[derive(Resource)]
 pub struct NetContext {
     pub messages: Arc<MessageRegistry>,
     pub config: SkynetConfig,
+
+    /// Errors reported outside of systems that can write events.
+    errors: Receiver<NetError>,
 }
 
 impl NetContext {
     pub fn new(config: SkynetConfig) -> Self {
         Self {
-            messages: Arc::new(MessageRegistry::default()),
+            messages: Arc::new(MessageRegistry {
+                max_decompressed_size: config.limits.max_decompressed_size as usize,
+                ..default()
+            }),
+            errors: Receiver::new(config.general.channel_size as usize),
             config,
         }
     }
 
+    /// Queue an error to be sent as a NetError event.
+    pub(crate) fn report(&self, error: NetError) {
+        log::warn!("{error}");
+        self.errors.send(error);
+    }
+
     pub fn config(&self) -> &SkynetConfig {
         &self.config
     }
+
+    /// Register a message type, and insert the resources its NetSender and NetReceiver use.
+    /// Messages registered after startup are read with a NetReceiver, even with
+    /// "MessageOptions::with_events". Peers are sent our new message IDs at the end of the frame.
+    pub fn register_message<T>(&self, commands: &mut Commands, options: MessageOptions) -> Result<(), RegistryError>
+    where
+        T: DeserializeOwned + TypePath + Send + Sync
+    {
+        self.register::<T>(commands, options, false)
+    }
+
+    /// Register a message type, which is internal if the plugin sends it itself.
+    pub(crate) fn register<T>(&self, commands: &mut Commands, options: MessageOptions, internal: bool) -> Result<(), RegistryError>
+    where
+        T: DeserializeOwned + TypePath + Send + Sync
+    {
+        let capacity = match options.capacity {
+            0 => self.config.general.channel_size,
+            capacity => capacity,
+        };
+        let inbox = Arc::new(Inbox::<T>::new(capacity as usize, options.overflow));
+        let name = T::type_path();
+        let msg = Arc::new(
+            MessageType {
+                name,
+                id: options.key.id(name),
+                tx: Box::new(IncomingTx { inbox: inbox.clone() }),
+                options,
+                internal,
+                last: Default::default(),
+            }
+        );
+        self.messages.insert(msg.clone())?;
+        commands.insert_resource(OutgoingTx::<T> { message: msg, _marker: PhantomData });
+        commands.insert_resource(IncomingRx { inbox });
+        Ok(())
+    }
+
+    /// Unregister a message type, and remove the resources its NetSender and NetReceiver use.
+    /// Systems with a NetSender or NetReceiver of the type must not run afterwards.
+    /// Returns "false" if the message was not registered.
+    pub fn unregister_message<T>(&self, commands: &mut Commands) -> bool
+    where
+        T: DeserializeOwned + TypePath + Send + Sync
+    {
+        let id = self.messages.registry.read().values()
+            .find(|ty| ty.name == T::type_path())
+            .map(|ty| ty.id);
+        if id.and_then(|id| self.messages.remove(id)).is_none() {
+            return false;
+        }
+        commands.remove_resource::<OutgoingTx<T>>();
+        commands.remove_resource::<IncomingRx<T>>();
+        true
+    }
 }
 
 pub struct MessageType {
-    /// The fully-qualified path of the type and its identifier.
+    /// The fully-qualified path of the type.
     pub(crate) name: &'static str,
 
-    /// The XXH64 hash of the name.
+    /// The ID sent with the message, determined by the MessageKey.
     pub(crate) id: u64,
 
     /// Transmitter that deserializes and sends messages to the incoming rx.
     pub(crate) tx: Box<dyn DynamicTx>,
+
+    /// The options the message was registered with.
+    pub(crate) options: MessageOptions,
+
+    /// Whether the plugin sends the message itself. Internal messages are always sent with full headers.
+    pub(crate) internal: bool,
+
+    /// The last packet we broadcast, if the message is sticky.
+    pub(crate) last: Mutex<Option<Arc<[u8]>>>,
+}
+
+impl MessageType {
+    /// Remember a packet we broadcast, to send it to members that join later, if the message is sticky.
+    pub(crate) fn remember(&self, packet: &Arc<[u8]>) {
+        if self.options.sticky {
+            *self.last.lock() = Some(packet.clone());
+        }
+    }
+}
+
+/// Options for a message type, set with "SkynetAppExt::add_message_with".
+#[derive(Clone, Debug)]
+pub struct MessageOptions {
+    /// The maximum number of messages of this type accepted from each peer
+    /// per second. Zero means unlimited.
+    pub quota: u32,
+
+    /// Who may send the message to whom.
+    pub authority: Authority,
+
+    /// How urgently the message is sent when the outbound budget is exceeded,
+    /// relative to other messages. Defaults to 1.0, and negative values are treated as 0.
+    pub priority: f32,
+
+    /// When the payload of the message is compressed.
+    pub compression: Compression,
+
+    /// Whether the last broadcast message is sent again to members that join later.
+    pub sticky: bool,
+
+    /// Whether the message is delivered as NetMessage events and to observers,
+    /// instead of through a NetReceiver.
+    pub events: bool,
+
+    /// The number of received messages held until they are read.
+    /// Zero means "general.channel_size".
+    pub capacity: u32,
+
+    /// What happens to messages received while "capacity" messages are unread.
+    pub overflow: Overflow,
+
+    /// How the ID of the message is determined.
+    pub key: MessageKey,
+}
+
+impl Default for MessageOptions {
+    fn default() -> Self {
+        Self {
+            quota: 0,
+            authority: Authority::AnyToAny,
+            priority: 1.0,
+            compression: Compression::Never,
+            sticky: false,
+            events: false,
+            capacity: 0,
+            overflow: Overflow::DropNewest,
+            key: MessageKey::TypePath,
+        }
+    }
+}
+
+impl MessageOptions {
+    /// Set how urgently the message is sent relative to other messages.
+    pub fn with_priority(mut self, priority: f32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Set when the payload of the message is compressed.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Set who may send the message to whom.
+    pub fn with_authority(mut self, authority: Authority) -> Self {
+        self.authority = authority;
+        self
+    }
+
+    /// Set the maximum number of messages accepted from each peer per second.
+    pub fn with_quota(mut self, quota: u32) -> Self {
+        self.quota = quota;
+        self
+    }
+
+    /// Set whether the last broadcast message is sent again to members that join later,
+    /// such as the current map or match settings.
+    pub fn with_sticky(mut self, sticky: bool) -> Self {
+        self.sticky = sticky;
+        self
+    }
+
+    /// Identify the message by a stable name instead of its type path,
+    /// so the type can be moved or renamed without breaking compatibility.
+    pub fn with_name(mut self, name: &'static str) -> Self {
+        self.key = MessageKey::Name(name);
+        self
+    }
+
+    /// Identify the message by an explicit ID instead of its type path.
+    pub fn with_id(mut self, id: u64) -> Self {
+        self.key = MessageKey::Id(id);
+        self
+    }
+
+    /// Set the number of received messages held until they are read.
+    pub fn with_capacity(mut self, capacity: u32) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Set what happens to messages received while the capacity is reached.
+    pub fn with_overflow(mut self, overflow: Overflow) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Set whether the message is delivered as NetMessage events and to observers,
+    /// so several systems can read the same message.
+    pub fn with_events(mut self, events: bool) -> Self {
+        self.events = events;
+        self
+    }
+}
+
+/// How the ID of a message type is determined. Peers must use the same key for a message.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub enum MessageKey {
+    /// Hash the type path, which changes when the type is moved or renamed.
+    #[default]
+    TypePath,
+
+    /// Hash a stable name.
+    Name(&'static str),
+
+    /// Use the ID as is.
+    Id(u64),
+}
+
+impl MessageKey {
+    /// The ID of a message with this key and type path.
+    pub fn id(&self, type_path: &str) -> u64 {
+        match *self {
+            MessageKey::TypePath => message_id(type_path),
+            MessageKey::Name(name) => message_id(name),
+            MessageKey::Id(id) => id,
+        }
+    }
+}
+
+/// Declares the MessageKey of a message type next to its definition, such as
+/// "MessageKey::Name("game::PlayerInput")", so it keeps the same ID wherever it is defined.
+/// Usually derived, with "#[derive(StableMessage)]" and "#[stable_message(name = "game::PlayerInput")]"
+/// or "#[stable_message(id = 42)]". Register the message with "SkynetAppExt::add_stable_message".
+pub trait StableMessage {
+    const KEY: MessageKey;
+}
+
+pub use bevy_skynet_macros::StableMessage;
+
+/// What happens to a message received while the unread messages of its type are at capacity.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub enum Overflow {
+    /// Drop the received message.
+    #[default]
+    DropNewest,
+
+    /// Drop the oldest unread message to make room, warning as messages are dropped.
+    DropOldest,
+
+    /// Hold only the latest message, for state that is replaced by every update.
+    KeepLatest,
+
+    /// Hold every message, warning when the backlog grows past the capacity.
+    Unbounded,
+}
+
+type AuthorityFn = dyn Fn(UserId, &Backend) -> bool + Send + Sync;
+
+/// Who may send a message to whom. Enforced by the NetSender, and again
+/// by the MessageRegistry when the message is received.
+#[derive(Clone, Default)]
+pub enum Authority {
+    /// Any lobby member may send the message to any other.
+    #[default]
+    AnyToAny,
+
+    /// Only the host may send the message.
+    HostToClients,
+
+    /// Only clients may send the message, and only to the host.
+    /// Broadcasting the message sends it to the host.
+    ClientsToHost,
+
+    /// The message may be sent by users for which the function returns "true".
+    /// The function is evaluated by both the sender and the receiver.
+    Custom(Arc<AuthorityFn>),
+}
+
+impl Authority {
+    /// Whether the sender may send the message to the receiver, or broadcast it if "None".
+    pub(crate) fn permits(&self, sender: UserId, receiver: Option<UserId>, backend: &Backend) -> bool {
+        let host = backend.host_id();
+        match self {
+            Authority::AnyToAny => true,
+            Authority::HostToClients => host == Some(sender),
+            Authority::ClientsToHost => host.is_some() && host != Some(sender) && receiver.is_none_or(|to| host == Some(to)),
+            Authority::Custom(f) => f(sender, backend),
+        }
+    }
+}
+
+impl std::fmt::Debug for Authority {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Authority::AnyToAny => "AnyToAny",
+            Authority::HostToClients => "HostToClients",
+            Authority::ClientsToHost => "ClientsToHost",
+            Authority::Custom(_) => "Custom",
+        })
+    }
 }
 
 #[derive(Default)]
 pub struct MessageRegistry {
     registry: RwLock<BTreeMap<u64, Arc<MessageType>>>,
+
+    /// The largest size a compressed payload may decompress to. Zero is unlimited.
+    max_decompressed_size: usize,
+
+    /// The number of messages delivered, used to number them in order of arrival.
+    delivered: AtomicU64,
 }
 
 impl MessageRegistry {
-    pub fn insert(&self, msg: Arc<MessageType>) {
-        let name = msg.name;
-        if let Some(existing) = self.registry.write().insert(msg.id, msg) {
-            panic!("Failed to add Network Message '{}' because it has the same ID as the existing Message '{}'.", name, existing.name);
+    /// Add a message type. Fails if its ID is reserved or already registered,
+    /// or if the type is already registered under another ID.
+    pub fn insert(&self, msg: Arc<MessageType>) -> Result<(), RegistryError> {
+        if [RELAY_ID, FORWARD_ID, BATCH_ID].contains(&msg.id) {
+            return Err(RegistryError::Reserved { message: msg.name, id: msg.id });
+        }
+
+        let mut registry = self.registry.write();
+        if let Some(existing) = registry.get(&msg.id) {
+            return Err(RegistryError::Collision { message: msg.name, existing: existing.name });
         }
+        if registry.values().any(|ty| ty.name == msg.name) {
+            return Err(RegistryError::Duplicate { message: msg.name });
+        }
+        registry.insert(msg.id, msg);
+        Ok(())
+    }
+
+    /// Remove the message type with the ID.
+    pub fn remove(&self, msg_id: u64) -> Option<Arc<MessageType>> {
+        self.registry.write().remove(&msg_id)
     }
 
-    pub fn send(&self, msg_id: u64, payload: &[u8], sender: UserId) {
+    /// Whether the message with the ID is internal.
+    pub(crate) fn is_internal(&self, msg_id: u64) -> bool {
+        self.registry.read().get(&msg_id).is_some_and(|ty| ty.internal)
+    }
+
+    /// The index of the ID in the sorted registry.
+    pub(crate) fn index_of(&self, msg_id: u64) -> Option<usize> {
+        let registry = self.registry.read();
+        registry.contains_key(&msg_id).then(|| registry.range(..msg_id).count())
+    }
+
+    /// The sorted IDs of all registered messages.
+    pub fn ids(&self) -> Vec<u64> {
+        self.registry.read().keys().copied().collect()
+    }
+
+    /// The name of the message with the ID, if it is registered.
+    pub fn name_of(&self, msg_id: u64) -> Option<&'static str> {
+        self.registry.read().get(&msg_id).map(|ty| ty.name)
+    }
+
+    /// A hash of all registered IDs. Peers with the same digest have identical registries.
+    pub fn digest(&self) -> u64 {
+        let bytes = self.ids().iter().flat_map(|id| id.to_be_bytes()).collect::<Vec<_>>();
+        xxh64(&bytes, 0)
+    }
+
+    /// The last broadcast packets of sticky messages.
+    pub(crate) fn sticky(&self) -> Vec<Arc<[u8]>> {
+        self.registry.read().values()
+            .filter_map(|ty| ty.last.lock().clone())
+            .collect()
+    }
+
+    /// Forget the last broadcast packets of sticky messages.
+    pub(crate) fn clear_sticky(&self) {
+        for ty in self.registry.read().values() {
+            *ty.last.lock() = None;
+        }
+    }
+
+    /// Get the message type with the ID.
+    pub fn get(&self, msg_id: u64) -> Option<Arc<MessageType>> {
+        self.registry.read().get(&msg_id).cloned()
+    }
+
+    /// Deliver a received message to its IncomingRx, decompressing it if "compressed".
+    /// Messages the sender has no authority to send, or that fail to decompress,
+    /// are dropped and returned as errors.
+    pub(crate) fn send(&self, msg_id: u64, compressed: bool, payload: &[u8], arrival: Arrival, backend: &Backend) -> Result<(), NetError> {
+        let sender = arrival.sender;
         match self.registry.read().get(&msg_id) {
             None => log::error!("A message was received, but it was not registered in the NetContext."),
             Some(ty) => {
-                if let Err(e) = ty.tx.send(payload, sender) {
+                if !ty.options.authority.permits(sender, Some(backend.user_id()), backend) {
+                    return Err(NetError::Unauthorized { user: sender, message: ty.name });
+                }
+
+                let decompressed;
+                let payload = if compressed {
+                    decompressed = compression::decompress(payload, self.max_decompressed_size)
+                        .ok_or(NetError::Decompression { user: sender, message: ty.name })?;
+                    &decompressed[..]
+                } else {
+                    payload
+                };
+
+                let seq = self.delivered.fetch_add(1, Ordering::Relaxed);
+                if let Err(e) = ty.tx.send(payload, arrival, seq) {
                     log::error!("A message failed to deserialize with error: '{e}'.");
                 }
             }
         }
+        Ok(())
     }
 }
 
@@ -65,4 +462,130 @@ impl MessageRegistry {
 pub struct Message<T> {
     pub sender: UserId,
     pub payload: T,
-}
\ No newline at end of file
+
+    /// The order the message arrived in, across all message types. The Outbox
+    /// sends messages of the same priority to a peer in the order they were sent,
+    /// so for messages from the same sender registered with the same priority,
+    /// sorting by "seq" restores the order they were sent in. Messages of a higher
+    /// priority may overtake earlier messages of a lower priority.
+    pub seq: u64,
+
+    /// When the message was received, as the elapsed Time<Real>.
+    pub received: Duration,
+
+    /// The size of the payload as it was received, before decompression.
+    pub size: usize,
+
+    /// Whether the message came from the sender directly, or through the host.
+    pub delivery: Delivery,
+}
+
+/// How a message reached us.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Delivery {
+    /// Sent to us by the sender.
+    Direct,
+
+    /// Relayed to us by the host, with Topology::Star.
+    Relayed,
+}
+
+/// Where and when a packet was received, attached to the messages it carries.
+#[derive(Copy, Clone)]
+pub(crate) struct Arrival {
+    pub sender: UserId,
+    pub received: Duration,
+    pub size: usize,
+    pub delivery: Delivery,
+}
+
+/// A received message, delivered as an event for messages registered with "MessageOptions::with_events".
+/// Observers are triggered when the message is received, and EventReaders read it the frame after.
+#[derive(Event)]
+pub struct NetMessage<T: Send + Sync + 'static>(pub Message<T>);
+
+impl<T: Send + Sync + 'static> std::ops::Deref for NetMessage<T> {
+    type Target = Message<T>;
+
+    fn deref(&self) -> &Message<T> {
+        &self.0
+    }
+}
+
+/// Drain the IncomingRx of a message type, triggering observers and writing a NetMessage event for each message.
+pub fn send_message_events<T>(world: &mut World)
+where
+    T: DeserializeOwned + TypePath + Send + Sync
+{
+    // the message may have been unregistered at runtime.
+    let Some(rx) = world.get_resource::<IncomingRx<T>>() else { return };
+    let mut messages = Vec::new();
+    while let Some(message) = rx.inbox.pop() {
+        messages.push(message);
+    }
+
+    for message in messages {
+        let mut event = NetMessage(message);
+        world.trigger_ref(&mut event);
+        world.send_event(event);
+    }
+}
+
+/// Send the errors reported through the NetContext as events.
+pub fn send_net_errors(
+    mut context: ResMut<NetContext>,
+    mut on_error: EventWriter<NetError>,
+) {
+    for error in context.errors.iter() {
+        on_error.write(error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(StableMessage)]
+    #[stable_message(name = "game::PlayerInput")]
+    struct Named;
+
+    #[derive(StableMessage)]
+    #[stable_message(id = 42)]
+    struct WithId;
+
+    fn message(id: u64, sticky: bool) -> Arc<MessageType> {
+        Arc::new(MessageType {
+            name: Box::leak(format!("test::Message{id}").into_boxed_str()),
+            id,
+            tx: Box::new(IncomingTx { inbox: Arc::new(Inbox::<()>::new(1, Overflow::DropNewest)) }),
+            options: MessageOptions::default().with_sticky(sticky),
+            internal: false,
+            last: Default::default(),
+        })
+    }
+
+    #[test]
+    fn sticky_messages_keep_the_last_broadcast() {
+        let registry = MessageRegistry::default();
+        let (sticky, other) = (message(1, true), message(2, false));
+        registry.insert(sticky.clone()).unwrap();
+        registry.insert(other.clone()).unwrap();
+
+        let (first, second): (Arc<[u8]>, Arc<[u8]>) = (Arc::from(&[1][..]), Arc::from(&[2][..]));
+        sticky.remember(&first);
+        sticky.remember(&second);
+        other.remember(&first);
+        assert_eq!(registry.sticky(), [second]);
+
+        // leaving the lobby forgets them, so they are not sent in the next one.
+        registry.clear_sticky();
+        assert!(registry.sticky().is_empty());
+    }
+
+    #[test]
+    fn derived_keys() {
+        assert!(matches!(Named::KEY, MessageKey::Name("game::PlayerInput")));
+        assert_eq!(Named::KEY.id("any::Path"), message_id("game::PlayerInput"));
+        assert_eq!(WithId::KEY.id("any::Path"), 42);
+    }
+}
@@ -0,0 +1,102 @@
+//! Errors caused by remote peers, reported as events so the App can react to them.
+
+use bevy::prelude::*;
+use crate::backends::UserId;
+use crate::limits::LimitAction;
+
+/// A remote peer misbehaved, or a packet from it could not be delivered.
+#[derive(Event, Debug, Clone)]
+pub enum NetError {
+    /// The peer exceeded its packets/sec or bytes/sec budget.
+    RateLimited {
+        user: UserId,
+        action: LimitAction,
+    },
+
+    /// The peer exceeded the quota of a message type.
+    QuotaExceeded {
+        user: UserId,
+        message: &'static str,
+        action: LimitAction,
+    },
+
+    /// The peer registered different messages than we did, so packets
+    /// to it use full message IDs. "unknown" messages it registered are not
+    /// registered by us.
+    RegistryMismatch {
+        user: UserId,
+        unknown: usize,
+    },
+
+    /// A compressed message failed to decompress, or would have decompressed
+    /// to more than "limits.max_decompressed_size" bytes.
+    Decompression {
+        user: UserId,
+        message: &'static str,
+    },
+
+    /// A message was sent by a user without the authority to send it.
+    Unauthorized {
+        user: UserId,
+        message: &'static str,
+    },
+}
+
+/// A message type could not be registered.
+#[derive(Debug, Clone)]
+pub enum RegistryError {
+    /// Another registered message has the same ID.
+    Collision {
+        message: &'static str,
+        existing: &'static str,
+    },
+
+    /// The type is already registered, possibly under another ID.
+    Duplicate {
+        message: &'static str,
+    },
+
+    /// The ID is used internally.
+    Reserved {
+        message: &'static str,
+        id: u64,
+    },
+}
+
+impl std::fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegistryError::Collision { message, existing } => {
+                write!(f, "Message '{message}' has the same ID as the existing message '{existing}'")
+            }
+            RegistryError::Duplicate { message } => {
+                write!(f, "Message '{message}' is already registered")
+            }
+            RegistryError::Reserved { message, id } => {
+                write!(f, "Message '{message}' uses the reserved ID '{id:#x}'")
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for NetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NetError::RateLimited { user, action } => {
+                write!(f, "UserID '{user:?}' exceeded its rate limit (action: '{action}')")
+            }
+            NetError::QuotaExceeded { user, message, action } => {
+                write!(f, "UserID '{user:?}' exceeded the quota of message '{message}' (action: '{action}')")
+            }
+            NetError::RegistryMismatch { user, unknown } => {
+                write!(f, "UserID '{user:?}' registered different messages ('{unknown}' unknown to us)")
+            }
+            NetError::Decompression { user, message } => {
+                write!(f, "UserID '{user:?}' sent message '{message}', which failed to decompress")
+            }
+            NetError::Unauthorized { user, message } => {
+                write!(f, "UserID '{user:?}' has no authority to send message '{message}'")
+            }
+        }
+    }
+}
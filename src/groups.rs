@@ -0,0 +1,156 @@
+//! Named groups of lobby members, used to send a message to a subset of the lobby,
+//! such as the members of a team, with "NetSender::send_group".
+
+use std::collections::{HashMap, HashSet};
+use bevy::prelude::*;
+use crate::backends::{OnLobbyChange, OnLobbyExit, UserId};
+use crate::reconnect::{NetReconnect, OnGraceExpired};
+
+/// Named groups of users. Users are removed from every group when they
+/// leave the lobby, or once their slot is released if the host holds it for
+/// them to reconnect, and all groups are cleared when we leave it.
+#[derive(Resource, Default)]
+pub struct NetGroups {
+    groups: HashMap<String, HashSet<UserId>>,
+}
+
+impl NetGroups {
+    /// Add the user to the group, creating the group if it does not exist.
+    pub fn join(&mut self, group: &str, user: UserId) {
+        if let Some(members) = self.groups.get_mut(group) {
+            members.insert(user);
+        } else {
+            self.groups.insert(group.to_owned(), HashSet::from([user]));
+        }
+    }
+
+    /// Remove the user from the group. Empty groups are removed.
+    pub fn leave(&mut self, group: &str, user: UserId) {
+        if let Some(members) = self.groups.get_mut(group) {
+            members.remove(&user);
+            if members.is_empty() {
+                self.groups.remove(group);
+            }
+        }
+    }
+
+    /// Remove the user from every group.
+    pub fn leave_all(&mut self, user: UserId) {
+        self.groups.retain(|_, members| {
+            members.remove(&user);
+            !members.is_empty()
+        });
+    }
+
+    /// Remove the group and all of its members.
+    pub fn remove(&mut self, group: &str) {
+        self.groups.remove(group);
+    }
+
+    /// Whether the user is a member of the group.
+    pub fn contains(&self, group: &str, user: UserId) -> bool {
+        self.groups.get(group).is_some_and(|members| members.contains(&user))
+    }
+
+    /// The members of the group.
+    pub fn members(&self, group: &str) -> impl Iterator<Item = UserId> + '_ {
+        self.groups.get(group).into_iter().flatten().copied()
+    }
+
+    /// The names of all groups.
+    pub fn groups(&self) -> impl Iterator<Item = &str> {
+        self.groups.keys().map(String::as_str)
+    }
+
+    /// Remove all groups.
+    pub fn clear(&mut self) {
+        self.groups.clear();
+    }
+}
+
+/// Remove users from their groups when they leave the lobby,
+/// unless their slot is held for them to reconnect.
+pub fn update_net_groups(
+    reconnect: Res<NetReconnect>,
+    mut groups: ResMut<NetGroups>,
+    mut on_lobby_exit: EventReader<OnLobbyExit>,
+    mut on_lobby_change: EventReader<OnLobbyChange>,
+    mut on_grace_expired: EventReader<OnGraceExpired>,
+) {
+    if on_lobby_exit.read().count() > 0 {
+        groups.clear();
+    }
+
+    for ev in on_lobby_change.read() {
+        match ev {
+            OnLobbyChange::Joined(_) => {}
+            OnLobbyChange::Exited(user) if reconnect.is_held(*user) => {}
+            OnLobbyChange::Exited(user)
+            | OnLobbyChange::Kicked { target: user, .. }
+            | OnLobbyChange::Banned { target: user, .. } => groups.leave_all(*user),
+        }
+    }
+
+    for ev in on_grace_expired.read() {
+        groups.leave_all(ev.user);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::*;
+
+    fn user(id: u64) -> UserId {
+        UserId::from_raw(id)
+    }
+
+    fn app() -> App {
+        let mut app = App::new();
+        app.add_event::<OnLobbyExit>()
+            .add_event::<OnLobbyChange>()
+            .add_event::<OnGraceExpired>()
+            .init_resource::<NetGroups>()
+            .init_resource::<NetReconnect>()
+            .add_systems(Update, update_net_groups);
+        app
+    }
+
+    fn in_red(app: &App, user: UserId) -> bool {
+        app.world().resource::<NetGroups>().contains("red", user)
+    }
+
+    #[test]
+    fn held_members_keep_their_groups_until_the_slot_is_released() {
+        let (a, b) = (user(1), user(2));
+        let mut app = app();
+        app.world_mut().resource_mut::<NetGroups>().join("red", a);
+        app.world_mut().resource_mut::<NetGroups>().join("red", b);
+
+        // a's slot is held, b left for good.
+        app.world_mut().resource_mut::<NetReconnect>().hold(a, Duration::from_secs(5));
+        app.world_mut().send_event(OnLobbyChange::Exited(a));
+        app.world_mut().send_event(OnLobbyChange::Exited(b));
+        app.update();
+        assert!(in_red(&app, a));
+        assert!(!in_red(&app, b));
+
+        // a resumes and keeps its groups.
+        app.world_mut().resource_mut::<NetReconnect>().release(a);
+        app.world_mut().send_event(OnLobbyChange::Joined(a));
+        app.update();
+        assert!(in_red(&app, a));
+
+        // a leaves again and does not return in time.
+        app.world_mut().resource_mut::<NetReconnect>().hold(a, Duration::from_secs(5));
+        app.world_mut().send_event(OnLobbyChange::Exited(a));
+        app.update();
+        assert!(in_red(&app, a));
+
+        let expired = app.world_mut().resource_mut::<NetReconnect>().expire(Duration::from_secs(5));
+        assert_eq!(expired, [a]);
+        app.world_mut().send_event(OnGraceExpired { user: a });
+        app.update();
+        assert!(!in_red(&app, a));
+    }
+}
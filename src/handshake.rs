@@ -0,0 +1,395 @@
+//! Handshake comparing the MessageRegistries of peers.
+//!
+//! Every peer sends the sorted IDs of its registered messages to the peers it
+//! connects to. Packets to a peer with an identical registry use compact headers,
+//! since both sides assign the same index to every message. Peers with a
+//! different registry keep receiving full 8-byte IDs, and a NetError is sent
+//! so the mismatch can be reported to the user.
+//!
+//! A peer only sends compact headers once the other side confirmed, with
+//! "matched" in its IdTable, that it accepts them. Until then both sides use
+//! full headers, whichever IdTable arrives first. The IdTables themselves, like
+//! every internal message, are always sent with full headers.
+//!
+//! When messages are registered or unregistered after startup, our indices
+//! change, so we stop using compact headers and send our IdTable to every peer
+//! again, asking for theirs in reply. Compact headers from a peer keep being
+//! resolved with the IDs both sides agreed on last, until the peer answers with
+//! its IdTable. Packets it compacted before learning about the change are sent
+//! before that answer, so they still resolve to the message they were sent as.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use bevy::prelude::*;
+use bevy::log;
+use serde::{Deserialize, Serialize};
+use crate::backends::{Backend, IBackend, OnLobbyChange, OnLobbyExit, OnLobbyJoin, UserId};
+use crate::context::NetContext;
+use crate::error::NetError;
+use crate::params::{NetReceiver, NetSender};
+use crate::relay::Router;
+
+/// Internal message carrying the registered message IDs of a peer.
+#[derive(Serialize, Deserialize, TypePath)]
+pub struct IdTable {
+    /// The digest of the MessageRegistry.
+    pub digest: u64,
+
+    /// The sorted IDs of the registered messages.
+    pub ids: Vec<u64>,
+
+    /// Whether the receiver should reply with its own IdTable, because our registry changed.
+    pub reply: bool,
+
+    /// Whether the sender found the receiver's registry identical, and accepts its compact headers.
+    pub matched: bool,
+}
+
+/// The peers whose MessageRegistry is identical to ours.
+#[derive(Resource, Default)]
+pub struct CompactIds {
+    /// Peers whose compact headers we accept, with the sorted IDs their indices refer to.
+    accepted: HashMap<UserId, Arc<[u64]>>,
+
+    /// Peers that confirmed they accept our compact headers.
+    confirmed: HashSet<UserId>,
+}
+
+impl CompactIds {
+    /// Whether packets to the user use compact headers.
+    pub fn is_compact(&self, user: UserId) -> bool {
+        self.confirmed.contains(&user)
+    }
+
+    /// Whether packets from the user may use compact headers.
+    pub fn accepts(&self, user: UserId) -> bool {
+        self.accepted.contains_key(&user)
+    }
+
+    /// The sorted IDs the compact headers of the user refer to.
+    pub(crate) fn ids_of(&self, user: UserId) -> Option<&[u64]> {
+        self.accepted.get(&user).map(|ids| &**ids)
+    }
+
+    /// Update the state of the peer from the IdTable it sent, given the digest and sorted IDs of our registry.
+    /// Returns the "matched" value of the IdTable to reply with, if the peer needs a reply.
+    pub(crate) fn receive(&mut self, user: UserId, table: &IdTable, digest: u64, ids: &[u64]) -> Option<bool> {
+        let matches = table.digest == digest;
+        let newly_accepted = matches && self.accepted.get(&user).is_none_or(|accepted| **accepted != *ids);
+        if newly_accepted {
+            self.accepted.insert(user, ids.into());
+        } else if !matches {
+            self.accepted.remove(&user);
+        }
+
+        if matches && table.matched {
+            self.confirmed.insert(user);
+        } else {
+            self.confirmed.remove(&user);
+        }
+
+        // the peer has to learn that we accept its compact headers before it uses them.
+        let reply = table.reply || (matches && !table.matched) || newly_accepted;
+        reply.then_some(matches)
+    }
+
+    /// Stop using compact headers after our registry changed.
+    /// Compact headers from peers are still accepted until they send their IdTable.
+    pub(crate) fn registry_changed(&mut self) {
+        self.confirmed.clear();
+    }
+
+    pub(crate) fn forget(&mut self, user: UserId) {
+        self.accepted.remove(&user);
+        self.confirmed.remove(&user);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.accepted.clear();
+        self.confirmed.clear();
+    }
+}
+
+/// Send our IdTable to the members we connect to directly,
+/// and to every peer again when our MessageRegistry changes.
+#[allow(clippy::too_many_arguments)]
+pub fn send_id_tables(
+    context: Res<NetContext>,
+    backend: Res<Backend>,
+    router: Res<Router>,
+    mut compact: ResMut<CompactIds>,
+    mut on_lobby_join: EventReader<OnLobbyJoin>,
+    mut on_lobby_change: EventReader<OnLobbyChange>,
+    mut sender: NetSender<IdTable>,
+    mut digest: Local<Option<u64>>,
+) {
+    let current = context.messages.digest();
+    let changed = digest.is_some_and(|digest| digest != current);
+    *digest = Some(current);
+
+    if changed {
+        log::info!("The registered messages changed, repeating the handshake with peers.");
+        compact.registry_changed();
+    }
+
+    let mut peers = Vec::new();
+    if on_lobby_join.read().count() > 0 || changed {
+        peers.extend(backend.lobby_members());
+    }
+
+    for ev in on_lobby_change.read() {
+        match ev {
+            OnLobbyChange::Joined(user) if !peers.contains(user) => peers.push(*user),
+            _ => {}
+        }
+    }
+
+    peers.retain(|user| router.is_peer(&backend, *user));
+    if !peers.is_empty() {
+        let table = IdTable {
+            digest: current,
+            ids: context.messages.ids(),
+            reply: changed,
+            matched: false,
+        };
+        sender.send_many(&peers, &table);
+    }
+}
+
+/// Compare the IdTables of peers to our own, and forget peers that leave.
+pub fn recv_id_tables(
+    context: Res<NetContext>,
+    mut compact: ResMut<CompactIds>,
+    mut tables: NetReceiver<IdTable>,
+    mut on_lobby_exit: EventReader<OnLobbyExit>,
+    mut on_lobby_change: EventReader<OnLobbyChange>,
+    mut on_error: EventWriter<NetError>,
+    mut sender: NetSender<IdTable>,
+) {
+    if on_lobby_exit.read().count() > 0 {
+        compact.clear();
+    }
+
+    for ev in on_lobby_change.read() {
+        match ev {
+            OnLobbyChange::Joined(_) => {}
+            OnLobbyChange::Exited(user)
+            | OnLobbyChange::Kicked { target: user, .. }
+            | OnLobbyChange::Banned { target: user, .. } => compact.forget(*user),
+        }
+    }
+
+    let digest = context.messages.digest();
+    let ours = context.messages.ids();
+    while let Some(msg) = tables.recv() {
+        let user = msg.sender;
+        if let Some(matched) = compact.receive(user, &msg.payload, digest, &ours) {
+            let table = IdTable {
+                digest,
+                ids: ours.clone(),
+                reply: false,
+                matched,
+            };
+            sender.send(user, &table);
+        }
+
+        if msg.payload.digest == digest {
+            if compact.is_compact(user) {
+                log::debug!("UserID '{:?}' has the same messages registered, using compact headers.", user);
+            }
+            continue;
+        }
+
+        let theirs = msg.payload.ids.iter().copied().collect::<HashSet<_>>();
+        for id in ours.iter().filter(|id| !theirs.contains(id)) {
+            log::warn!("UserID '{:?}' has not registered message '{}'", user, context.messages.name_of(*id).unwrap_or("?"));
+        }
+
+        let unknown = theirs.iter().filter(|id| !ours.contains(id)).count();
+        let error = NetError::RegistryMismatch { user, unknown };
+        log::warn!("{error}");
+        on_error.write(error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header;
+
+    const A: u64 = 1;
+    const B: u64 = 2;
+
+    fn user(id: u64) -> UserId {
+        UserId::from_raw(id)
+    }
+
+    /// A peer with the sorted IDs of its registry.
+    struct Peer {
+        compact: CompactIds,
+        ids: Vec<u64>,
+    }
+
+    impl Peer {
+        fn new(ids: &[u64]) -> Self {
+            Peer { compact: CompactIds::default(), ids: ids.to_vec() }
+        }
+
+        fn digest(&self) -> u64 {
+            self.ids.iter().fold(17, |digest, id| digest.wrapping_mul(31).wrapping_add(*id))
+        }
+
+        fn table(&self, reply: bool, matched: bool) -> IdTable {
+            IdTable { digest: self.digest(), ids: self.ids.clone(), reply, matched }
+        }
+
+        /// Deliver a packet from the peer with the ID.
+        fn expand(&self, from: u64, packet: &[u8]) -> Option<u64> {
+            let ids = self.compact.ids_of(user(from)).unwrap_or(&[]);
+            header::read(&header::expand(packet, ids)?).map(|header| header.id)
+        }
+    }
+
+    /// Deliver IdTables between two peers until neither replies, returning the number delivered.
+    fn exchange(peers: &mut [Peer; 2], mut queue: Vec<(usize, IdTable)>) -> usize {
+        let mut delivered = 0;
+        while !queue.is_empty() {
+            let (to, table) = queue.remove(0);
+            let from = 1 - to;
+            let peer = &mut peers[to];
+            let digest = peer.digest();
+            if let Some(matched) = peer.compact.receive(user(from as u64 + 1), &table, digest, &peer.ids) {
+                queue.push((from, peer.table(false, matched)));
+            }
+            delivered += 1;
+            assert!(delivered < 16, "the handshake does not terminate");
+        }
+        delivered
+    }
+
+    fn is_compact(peers: &[Peer; 2]) -> bool {
+        peers[0].compact.is_compact(user(B)) && peers[0].compact.accepts(user(B))
+            && peers[1].compact.is_compact(user(A)) && peers[1].compact.accepts(user(A))
+    }
+
+    /// A packet with a compact header holding the index.
+    fn compacted(index: u8) -> Vec<u8> {
+        vec![header::COMPACT, index, 0xff]
+    }
+
+    #[test]
+    fn both_sides_send_on_join() {
+        let mut peers = [Peer::new(&[7]), Peer::new(&[7])];
+        let tables = vec![(1, peers[0].table(false, false)), (0, peers[1].table(false, false))];
+        exchange(&mut peers, tables);
+        assert!(is_compact(&peers));
+    }
+
+    #[test]
+    fn one_side_sends_on_join() {
+        let mut peers = [Peer::new(&[7]), Peer::new(&[7])];
+        let table = peers[0].table(false, false);
+        exchange(&mut peers, vec![(1, table)]);
+        assert!(is_compact(&peers));
+    }
+
+    #[test]
+    fn compact_headers_are_never_sent_before_they_are_accepted() {
+        let mut a = Peer::new(&[7]);
+        let mut b = Peer::new(&[7]);
+
+        // B received A's table, but its reply has not arrived yet.
+        let reply = b.compact.receive(user(A), &a.table(false, false), b.digest(), &b.ids);
+        assert_eq!(reply, Some(true));
+        assert!(!b.compact.is_compact(user(A)));
+        assert!(!a.compact.accepts(user(B)));
+
+        a.compact.receive(user(B), &b.table(false, true), a.digest(), &a.ids);
+        assert!(a.compact.is_compact(user(B)));
+        assert!(!b.compact.is_compact(user(A)));
+    }
+
+    #[test]
+    fn mismatched_registries_use_full_headers() {
+        let mut peers = [Peer::new(&[7]), Peer::new(&[8])];
+        let tables = vec![(1, peers[0].table(false, false)), (0, peers[1].table(false, false))];
+        exchange(&mut peers, tables);
+        assert!(!peers[0].compact.is_compact(user(B)) && !peers[0].compact.accepts(user(B)));
+        assert!(!peers[1].compact.is_compact(user(A)) && !peers[1].compact.accepts(user(A)));
+    }
+
+    #[test]
+    fn registry_change_repeats_the_handshake() {
+        let mut peers = [Peer::new(&[7]), Peer::new(&[7])];
+        let table = peers[0].table(false, false);
+        exchange(&mut peers, vec![(1, table)]);
+
+        // A registers a message, B has not yet.
+        peers[0].ids.push(9);
+        peers[0].compact.registry_changed();
+        let table = peers[0].table(true, false);
+        exchange(&mut peers, vec![(1, table)]);
+        assert!(!peers[0].compact.accepts(user(B)) && !peers[1].compact.is_compact(user(A)));
+        assert!(!peers[1].compact.accepts(user(A)));
+
+        // B registers the same message.
+        peers[1].ids.push(9);
+        peers[1].compact.registry_changed();
+        let table = peers[1].table(true, false);
+        exchange(&mut peers, vec![(0, table)]);
+        assert!(is_compact(&peers));
+    }
+
+    #[test]
+    fn compact_packets_in_flight_survive_a_registry_change() {
+        let mut peers = [Peer::new(&[10, 20, 30]), Peer::new(&[10, 20, 30])];
+        let table = peers[0].table(false, false);
+        exchange(&mut peers, vec![(1, table)]);
+        assert!(is_compact(&peers));
+
+        // B compacts message 20 to index 1, then A registers message 15 before it arrives.
+        let in_flight = compacted(1);
+        peers[0].ids.insert(1, 15);
+        peers[0].compact.registry_changed();
+        assert!(!peers[0].compact.is_compact(user(B)));
+        assert_eq!(peers[0].expand(B, &in_flight), Some(20));
+
+        // B learns about the change, stops compacting and answers, after the packets it already sent.
+        let table = peers[0].table(true, false);
+        let digest = peers[1].digest();
+        let matched = peers[1].compact.receive(user(A), &table, digest, &peers[1].ids.clone());
+        assert_eq!(matched, Some(false));
+        assert!(!peers[1].compact.is_compact(user(A)));
+        assert_eq!(peers[0].expand(B, &in_flight), Some(20));
+
+        let table = peers[1].table(false, false);
+        exchange(&mut peers, vec![(0, table)]);
+        assert_eq!(peers[0].expand(B, &in_flight), None);
+
+        // Once both registered message 15, its index refers to it again.
+        peers[1].ids.insert(1, 15);
+        peers[1].compact.registry_changed();
+        let table = peers[1].table(true, false);
+        exchange(&mut peers, vec![(0, table)]);
+        assert!(is_compact(&peers));
+        assert_eq!(peers[0].expand(B, &compacted(1)), Some(15));
+        assert_eq!(peers[1].expand(A, &compacted(2)), Some(20));
+    }
+
+    #[test]
+    fn compact_packets_in_flight_while_the_peer_changes_its_registry() {
+        let mut peers = [Peer::new(&[10, 20, 30]), Peer::new(&[10, 20, 30])];
+        let table = peers[0].table(false, false);
+        exchange(&mut peers, vec![(1, table)]);
+
+        // A compacts message 30 to index 2, then B registers message 25 and sends its table.
+        let in_flight = compacted(2);
+        peers[1].ids.insert(2, 25);
+        peers[1].compact.registry_changed();
+        assert_eq!(peers[1].expand(A, &in_flight), Some(30));
+
+        let table = peers[1].table(true, false);
+        exchange(&mut peers, vec![(0, table)]);
+        assert!(!peers[0].compact.is_compact(user(B)) && !peers[1].compact.accepts(user(A)));
+    }
+}
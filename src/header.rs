@@ -0,0 +1,212 @@
+//! The header at the start of every packet, identifying its message type.
+//!
+//! Wire format:
+//!  - Full:    [flags][8 byte big-endian message ID]
+//!  - Compact: [flags | COMPACT][varint index of the ID in the sorted MessageRegistry]
+//!
+//! Flags other than COMPACT describe the payload, such as COMPRESSED.
+//!
+//! Packets are always written with a full header. The Outbox replaces it with a
+//! compact header when sending to a peer whose MessageRegistry was found to be
+//! identical to ours during the handshake, so both sides agree on the indices.
+//! Internal messages, such as the handshake itself, are never compacted, and
+//! neither are the reserved IDs of envelopes, which are not registered.
+//!
+//! Received compact headers are expanded back into full ones with the indices
+//! the sender agreed on, which are kept until it agrees on new ones, so packets
+//! in flight while a registry changes still resolve to the right message.
+
+use std::borrow::Cow;
+use crate::context::MessageRegistry;
+
+/// The header holds an index into the sorted MessageRegistry instead of an ID.
+pub(crate) const COMPACT: u8 = 1 << 0;
+
+/// The payload is compressed.
+pub(crate) const COMPRESSED: u8 = 1 << 1;
+
+/// The size of a full header.
+pub(crate) const FULL_LEN: usize = 9;
+
+/// A parsed packet header.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Header {
+    /// The message ID.
+    pub id: u64,
+
+    /// Flags other than COMPACT.
+    pub flags: u8,
+
+    /// The size of the header, after which the payload begins.
+    pub len: usize,
+}
+
+/// Write a full header to the buffer.
+pub(crate) fn write(buf: &mut Vec<u8>, id: u64, flags: u8) {
+    buf.push(flags & !COMPACT);
+    buf.extend_from_slice(&id.to_be_bytes());
+}
+
+/// Whether the packet starts with a compact header.
+pub(crate) fn is_compact(packet: &[u8]) -> bool {
+    packet.first().is_some_and(|flags| flags & COMPACT != 0)
+}
+
+/// Read the full header of a packet. Returns "None" if the header is truncated,
+/// or compact and not yet expanded.
+pub(crate) fn read(packet: &[u8]) -> Option<Header> {
+    let (&flags, rest) = packet.split_first()?;
+    if flags & COMPACT != 0 {
+        return None;
+    }
+    let id = u64::from_be_bytes(rest.get(..8)?.try_into().ok()?);
+    Some(Header { id, flags, len: FULL_LEN })
+}
+
+/// Replace the full header of a packet with a compact one.
+/// Returns "None" if the message is internal or not in the MessageRegistry.
+pub(crate) fn compact(packet: &[u8], registry: &MessageRegistry) -> Option<Vec<u8>> {
+    let header = read(packet)?;
+    if registry.is_internal(header.id) {
+        return None;
+    }
+    let index = registry.index_of(header.id)?;
+
+    let mut compacted = Vec::with_capacity(packet.len());
+    compacted.push(header.flags | COMPACT);
+    write_varint(&mut compacted, index as u64);
+    compacted.extend_from_slice(&packet[header.len..]);
+    Some(compacted)
+}
+
+/// Replace the compact header of a packet with a full one, given the sorted IDs
+/// the sender indexes. Packets with a full header are returned as they are.
+/// Returns "None" if the header is truncated, or the index is outside of the IDs.
+pub(crate) fn expand<'a>(packet: &'a [u8], ids: &[u64]) -> Option<Cow<'a, [u8]>> {
+    let (&flags, rest) = packet.split_first()?;
+    if flags & COMPACT == 0 {
+        return Some(Cow::Borrowed(packet));
+    }
+
+    let (index, len) = read_varint(rest)?;
+    let id = *ids.get(usize::try_from(index).ok()?)?;
+    let mut expanded = Vec::with_capacity(FULL_LEN + rest.len() - len);
+    write(&mut expanded, id, flags);
+    expanded.extend_from_slice(&rest[len..]);
+    Some(Cow::Owned(expanded))
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+fn read_varint(buf: &[u8]) -> Option<(u64, usize)> {
+    let mut n = 0u64;
+    for (i, &byte) in buf.iter().enumerate().take(10) {
+        n |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((n, i + 1));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use super::*;
+    use crate::comms::{Inbox, IncomingTx};
+    use crate::context::{MessageOptions, MessageType, Overflow};
+
+    fn registry(ids: &[(u64, bool)]) -> MessageRegistry {
+        let registry = MessageRegistry::default();
+        for &(id, internal) in ids {
+            registry.insert(Arc::new(MessageType {
+                name: Box::leak(format!("test::Message{id}").into_boxed_str()),
+                id,
+                tx: Box::new(IncomingTx { inbox: Arc::new(Inbox::<()>::new(1, Overflow::DropNewest)) }),
+                options: MessageOptions::default(),
+                internal,
+                last: Default::default(),
+            })).unwrap();
+        }
+        registry
+    }
+
+    #[test]
+    fn varint_bounds() {
+        let cases = [
+            (0, 1), (127, 1), (128, 2), (16_383, 2), (16_384, 3),
+            (u32::MAX as u64, 5), (1 << 63, 10), (u64::MAX, 10),
+        ];
+        for (n, len) in cases {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, n);
+            assert_eq!(buf.len(), len, "length of {n}");
+            assert_eq!(read_varint(&buf), Some((n, len)));
+
+            // trailing bytes belong to the payload.
+            buf.push(0xff);
+            assert_eq!(read_varint(&buf), Some((n, len)));
+        }
+    }
+
+    #[test]
+    fn malformed_varints_are_rejected() {
+        assert_eq!(read_varint(&[]), None);
+        assert_eq!(read_varint(&[0x80]), None);
+        assert_eq!(read_varint(&[0xff; 9]), None);
+        assert_eq!(read_varint(&[0xff; 11]), None);
+    }
+
+    #[test]
+    fn full_headers() {
+        let mut packet = Vec::new();
+        write(&mut packet, 0x0123_4567_89ab_cdef, COMPRESSED | COMPACT);
+        packet.push(42);
+
+        let header = read(&packet).unwrap();
+        assert_eq!(header.id, 0x0123_4567_89ab_cdef);
+        assert_eq!(header.flags, COMPRESSED);
+        assert_eq!(header.len, FULL_LEN);
+        assert!(read(&packet[..FULL_LEN - 1]).is_none());
+        assert!(read(&[]).is_none());
+        assert!(matches!(expand(&packet, &[]), Some(Cow::Borrowed(_))));
+    }
+
+    #[test]
+    fn compact_headers() {
+        let ids = (0..200).map(|i| (i * 1000, false)).collect::<Vec<_>>();
+        let registry = registry(&ids);
+        for id in [0, 127_000, 128_000, 199_000] {
+            let mut packet = Vec::new();
+            write(&mut packet, id, COMPRESSED);
+            packet.extend_from_slice(b"payload");
+
+            let compacted = compact(&packet, &registry).unwrap();
+            assert!(compacted.len() < packet.len());
+            assert!(is_compact(&compacted) && read(&compacted).is_none());
+            assert_eq!(expand(&compacted, &registry.ids()).unwrap(), packet);
+        }
+
+        // indices outside of the registry.
+        assert!(expand(&[COMPACT, 200, 1], &registry.ids()).is_none());
+        assert!(expand(&[COMPACT, 0x80], &registry.ids()).is_none());
+    }
+
+    #[test]
+    fn internal_and_unknown_messages_are_not_compacted() {
+        let registry = registry(&[(1, false), (2, true)]);
+        let mut internal = Vec::new();
+        write(&mut internal, 2, 0);
+        assert!(compact(&internal, &registry).is_none());
+
+        let mut unknown = Vec::new();
+        write(&mut unknown, 3, 0);
+        assert!(compact(&unknown, &registry).is_none());
+    }
+}
@@ -0,0 +1,213 @@
+//! Detection of peers that went silent.
+//!
+//! Every peer sends a Heartbeat to the peers it connects to directly every
+//! "general.heartbeat_interval" seconds. A peer we have not heard from for
+//! "general.lag_threshold" seconds is Lagging, and for "general.peer_timeout"
+//! seconds is TimedOut. A crashed peer may stay in the lobby's member list
+//! for a long time, so the OnPeerHealth events are the earliest sign of it.
+
+use std::collections::HashMap;
+use std::time::Duration;
+use bevy::prelude::*;
+use bevy::log;
+use serde::{Deserialize, Serialize};
+use crate::backends::{Backend, IBackend, UserId};
+use crate::context::NetContext;
+use crate::params::{NetReceiver, NetSender};
+use crate::relay::Router;
+
+/// Internal message telling a peer we are still connected.
+#[derive(Serialize, Deserialize, TypePath)]
+pub struct Heartbeat;
+
+/// How recently we heard from a peer.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum PeerHealth {
+    Healthy,
+
+    /// No heartbeat arrived for "general.lag_threshold" seconds.
+    Lagging,
+
+    /// No heartbeat arrived for "general.peer_timeout" seconds.
+    TimedOut,
+}
+
+impl std::fmt::Display for PeerHealth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use PeerHealth::*;
+        f.write_str(match *self {
+            Healthy => "Healthy",
+            Lagging => "Lagging",
+            TimedOut => "Timed Out",
+        })
+    }
+}
+
+/// The health of a peer changed.
+#[derive(Event, Debug, Clone)]
+pub struct OnPeerHealth {
+    pub user: UserId,
+    pub health: PeerHealth,
+}
+
+struct Peer {
+    health: PeerHealth,
+
+    /// When the last heartbeat arrived.
+    last_seen: Duration,
+}
+
+/// The health of the peers we connect to directly.
+#[derive(Resource, Default)]
+pub struct NetHealth {
+    peers: HashMap<UserId, Peer>,
+
+    /// When we last sent heartbeats.
+    last_sent: Duration,
+}
+
+impl NetHealth {
+    /// The health of the peer, if we connect to it directly.
+    pub fn health(&self, user: UserId) -> Option<PeerHealth> {
+        self.peers.get(&user).map(|peer| peer.health)
+    }
+
+    /// How long ago the last heartbeat of the peer arrived, measured in real time.
+    pub fn silent_for(&self, user: UserId, now: Duration) -> Option<Duration> {
+        self.peers.get(&user).map(|peer| now.saturating_sub(peer.last_seen))
+    }
+
+    /// The peers that are not Healthy.
+    pub fn unhealthy(&self) -> impl Iterator<Item = (UserId, PeerHealth)> + '_ {
+        self.peers.iter()
+            .filter(|(_, peer)| peer.health != PeerHealth::Healthy)
+            .map(|(user, peer)| (*user, peer.health))
+    }
+
+    /// Forget peers that left, and give new peers a full timeout to send their first heartbeat.
+    fn track(&mut self, peers: &[UserId], now: Duration) {
+        self.peers.retain(|user, _| peers.contains(user));
+        for user in peers {
+            self.peers.entry(*user).or_insert(Peer { health: PeerHealth::Healthy, last_seen: now });
+        }
+    }
+
+    /// A heartbeat arrived from the user.
+    fn heard_from(&mut self, user: UserId, now: Duration) {
+        if let Some(peer) = self.peers.get_mut(&user) {
+            peer.last_seen = now;
+        }
+    }
+
+    /// Update the health of the peers, returning those whose health changed.
+    fn update(&mut self, now: Duration, lag_threshold: Duration, timeout: Duration) -> Vec<(UserId, PeerHealth, Duration)> {
+        let mut changed = Vec::new();
+        for (user, peer) in self.peers.iter_mut() {
+            let silent = now.saturating_sub(peer.last_seen);
+            let current = if silent >= timeout {
+                PeerHealth::TimedOut
+            } else if silent >= lag_threshold {
+                PeerHealth::Lagging
+            } else {
+                PeerHealth::Healthy
+            };
+
+            if current != peer.health {
+                peer.health = current;
+                changed.push((*user, current, silent));
+            }
+        }
+        changed
+    }
+}
+
+/// Send heartbeats to our peers, and update their health from the heartbeats received.
+#[allow(clippy::too_many_arguments)]
+pub fn update_peer_health(
+    context: Res<NetContext>,
+    backend: Res<Backend>,
+    router: Res<Router>,
+    time: Res<Time<Real>>,
+    mut health: ResMut<NetHealth>,
+    mut heartbeats: NetReceiver<Heartbeat>,
+    mut sender: NetSender<Heartbeat>,
+    mut on_peer_health: EventWriter<OnPeerHealth>,
+) {
+    let now = time.elapsed();
+    let config = &context.config.general;
+    let peers = backend.lobby_members().into_iter()
+        .filter(|user| router.is_peer(&backend, *user))
+        .collect::<Vec<_>>();
+
+    health.track(&peers, now);
+
+    let interval = Duration::from_secs_f32(config.heartbeat_interval);
+    if !peers.is_empty() && now.saturating_sub(health.last_sent) >= interval {
+        health.last_sent = now;
+        sender.send_many(&peers, &Heartbeat);
+    }
+
+    while let Some(msg) = heartbeats.recv() {
+        health.heard_from(msg.sender, now);
+    }
+
+    let lag_threshold = Duration::from_secs_f32(config.lag_threshold);
+    let timeout = Duration::from_secs_f32(config.peer_timeout);
+    for (user, current, silent) in health.update(now, lag_threshold, timeout) {
+        if current == PeerHealth::Healthy {
+            log::info!("UserID '{:?}' is Healthy again", user);
+        } else {
+            log::warn!("UserID '{:?}' is {} (silent for '{:.1}' seconds)", user, current, silent.as_secs_f32());
+        }
+        on_peer_health.write(OnPeerHealth { user, health: current });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(id: u64) -> UserId {
+        UserId::from_raw(id)
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn silent_peers_lag_then_time_out() {
+        let (a, b) = (user(1), user(2));
+        let mut health = NetHealth::default();
+        health.track(&[a, b], secs(0));
+        assert_eq!(health.health(a), Some(PeerHealth::Healthy));
+
+        health.heard_from(b, secs(2));
+        assert_eq!(health.update(secs(3), secs(3), secs(10)), [(a, PeerHealth::Lagging, secs(3))]);
+        assert!(health.update(secs(4), secs(3), secs(10)).is_empty());
+        assert_eq!(health.unhealthy().collect::<Vec<_>>(), [(a, PeerHealth::Lagging)]);
+
+        health.heard_from(b, secs(9));
+        assert_eq!(health.update(secs(10), secs(3), secs(10)), [(a, PeerHealth::TimedOut, secs(10))]);
+        assert_eq!(health.silent_for(a, secs(10)), Some(secs(10)));
+
+        // a heartbeat makes a peer Healthy again.
+        health.heard_from(a, secs(11));
+        assert_eq!(health.update(secs(11), secs(3), secs(10)), [(a, PeerHealth::Healthy, secs(0))]);
+    }
+
+    #[test]
+    fn peers_that_leave_are_forgotten() {
+        let (a, b) = (user(1), user(2));
+        let mut health = NetHealth::default();
+        health.track(&[a], secs(0));
+        health.track(&[b], secs(5));
+        assert_eq!(health.health(a), None);
+        health.heard_from(a, secs(6));
+        assert_eq!(health.health(a), None);
+
+        // new peers get a full timeout from when they are first seen.
+        assert!(health.update(secs(7), secs(3), secs(10)).is_empty());
+        assert_eq!(health.silent_for(b, secs(7)), Some(secs(2)));
+    }
+}
@@ -0,0 +1,213 @@
+//! Interest management, which decides which entities are relevant to each lobby member.
+//!
+//! Entities with an InterestSource are relevant to a peer when they are within the
+//! peer's InterestArea and, if the source belongs to a group, the peer is a member
+//! of that group in the NetGroups. Peers without an InterestArea are interested
+//! in everything, so interest management only applies once an area is set.
+//!
+//! The relevant entities are recomputed at the start of every frame, from the
+//! transforms propagated in the previous one, so systems sending updates see
+//! the current lobby members and areas. A custom rule may be set with
+//! "SkynetAppExt::set_relevance_filter".
+//!
+//! Interest management is opt-in: only "NetSender::send_interested" and
+//! "NetSender::send_packet_interested" skip peers an update is not relevant to.
+//! Broadcasts and other sends reach every recipient, since they are not about
+//! a particular entity.
+
+use std::collections::{HashMap, HashSet};
+use bevy::prelude::*;
+use crate::backends::{Backend, IBackend, UserId};
+use crate::groups::NetGroups;
+use crate::reconnect::NetReconnect;
+
+/// Marks an entity whose updates are only sent to the peers it is relevant to.
+/// Its position is read from its GlobalTransform.
+#[derive(Component, Clone, Debug, Default)]
+pub struct InterestSource {
+    /// Only members of this group are interested in the entity.
+    pub group: Option<String>,
+
+    /// The entity is relevant to every peer, regardless of distance.
+    pub global: bool,
+}
+
+/// The area of the world a peer is interested in.
+#[derive(Clone, Copy, Debug)]
+pub struct InterestArea {
+    pub position: Vec3,
+    pub radius: f32,
+}
+
+impl InterestArea {
+    pub fn contains(&self, position: Vec3) -> bool {
+        self.position.distance_squared(position) <= self.radius * self.radius
+    }
+}
+
+type RelevanceFn = dyn Fn(UserId, Option<&InterestArea>, Entity, Vec3) -> bool + Send + Sync;
+
+/// User-supplied rule deciding whether an entity is relevant to a peer,
+/// used instead of the InterestArea. Set with "SkynetAppExt::set_relevance_filter".
+#[derive(Resource)]
+pub struct RelevanceFilter(pub(crate) Box<RelevanceFn>);
+
+/// The interest areas of the lobby members, and the entities relevant to each.
+#[derive(Resource, Default)]
+pub struct NetInterest {
+    areas: HashMap<UserId, InterestArea>,
+    relevant: HashMap<UserId, HashSet<Entity>>,
+}
+
+impl NetInterest {
+    /// Set the area of the world the peer is interested in.
+    pub fn set_area(&mut self, user: UserId, area: InterestArea) {
+        self.areas.insert(user, area);
+    }
+
+    /// Make the peer interested in everything again.
+    pub fn clear_area(&mut self, user: UserId) {
+        self.areas.remove(&user);
+    }
+
+    pub fn area(&self, user: UserId) -> Option<&InterestArea> {
+        self.areas.get(&user)
+    }
+
+    /// Whether the entity was relevant to the peer as of the last update.
+    pub fn is_relevant(&self, user: UserId, entity: Entity) -> bool {
+        self.relevant.get(&user).is_some_and(|entities| entities.contains(&entity))
+    }
+
+    /// The entities relevant to the peer as of the last update.
+    pub fn relevant_to(&self, user: UserId) -> impl Iterator<Item = Entity> + '_ {
+        self.relevant.get(&user).into_iter().flatten().copied()
+    }
+
+    /// The peers the entity was relevant to as of the last update.
+    pub fn interested_in(&self, entity: Entity) -> impl Iterator<Item = UserId> + '_ {
+        self.relevant.iter()
+            .filter(move |(_, entities)| entities.contains(&entity))
+            .map(|(user, _)| *user)
+    }
+
+    /// Recompute the entities relevant to each member, and forget the areas of peers
+    /// that left, unless their slot is held for them to reconnect.
+    fn update<'a>(
+        &mut self,
+        members: &[UserId],
+        reconnect: &NetReconnect,
+        groups: &NetGroups,
+        filter: Option<&RelevanceFilter>,
+        sources: impl Iterator<Item = (Entity, &'a InterestSource, Vec3)> + Clone,
+    ) {
+        self.areas.retain(|user, _| members.contains(user) || reconnect.is_held(*user));
+        self.relevant.clear();
+
+        for &user in members {
+            let area = self.areas.get(&user);
+            let relevant = sources.clone()
+                .filter(|(entity, source, position)| {
+                    if let Some(filter) = filter {
+                        return (filter.0)(user, area, *entity, *position);
+                    }
+
+                    source.global || (
+                        source.group.as_deref().is_none_or(|group| groups.contains(group, user))
+                        && area.is_none_or(|area| area.contains(*position))
+                    )
+                })
+                .map(|(entity, _, _)| entity)
+                .collect();
+            self.relevant.insert(user, relevant);
+        }
+    }
+}
+
+/// Recompute the entities relevant to each lobby member, and forget
+/// the areas of peers that left once their slot is released.
+pub fn update_interest(
+    backend: Res<Backend>,
+    reconnect: Res<NetReconnect>,
+    groups: Res<NetGroups>,
+    filter: Option<Res<RelevanceFilter>>,
+    mut interest: ResMut<NetInterest>,
+    sources: Query<(Entity, &InterestSource, &GlobalTransform)>,
+) {
+    let sources = sources.iter().map(|(entity, source, transform)| (entity, source, transform.translation()));
+    interest.update(&backend.lobby_members(), &reconnect, &groups, filter.as_deref(), sources);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::*;
+
+    fn user(id: u64) -> UserId {
+        UserId::from_raw(id)
+    }
+
+    fn source(group: Option<&str>, global: bool) -> InterestSource {
+        InterestSource { group: group.map(Into::into), global }
+    }
+
+    fn relevant(interest: &NetInterest, user: UserId) -> Vec<Entity> {
+        let mut entities = interest.relevant_to(user).collect::<Vec<_>>();
+        entities.sort();
+        entities
+    }
+
+    #[test]
+    fn areas_groups_and_global_sources() {
+        let (a, b, c) = (user(1), user(2), user(3));
+        let mut groups = NetGroups::default();
+        groups.join("red", a);
+
+        let sources = [
+            (Entity::from_raw(0), source(None, false), Vec3::ZERO),
+            (Entity::from_raw(1), source(None, false), Vec3::X * 100.0),
+            (Entity::from_raw(2), source(Some("red"), false), Vec3::ZERO),
+            (Entity::from_raw(3), source(Some("red"), true), Vec3::X * 100.0),
+        ];
+        let sources = sources.iter().map(|(entity, source, position)| (*entity, source, *position));
+
+        let mut interest = NetInterest::default();
+        interest.set_area(a, InterestArea { position: Vec3::ZERO, radius: 10.0 });
+        interest.set_area(b, InterestArea { position: Vec3::ZERO, radius: 10.0 });
+        interest.update(&[a, b, c], &NetReconnect::default(), &groups, None, sources.clone());
+
+        let entities = |ids: &[u32]| ids.iter().map(|id| Entity::from_raw(*id)).collect::<Vec<_>>();
+        assert_eq!(relevant(&interest, a), entities(&[0, 2, 3]));
+        assert_eq!(relevant(&interest, b), entities(&[0, 3]));
+        // without an area, a peer is interested in everything outside of other groups.
+        assert_eq!(relevant(&interest, c), entities(&[0, 1, 3]));
+        assert_eq!(interest.interested_in(Entity::from_raw(1)).collect::<Vec<_>>(), [c]);
+
+        // peers that left keep their area while their slot is held, and are forgotten once it is released.
+        let mut reconnect = NetReconnect::default();
+        reconnect.hold(b, Duration::from_secs(5));
+        interest.update(&[a], &reconnect, &groups, None, sources.clone());
+        assert!(interest.area(b).is_some());
+        assert!(!interest.is_relevant(b, Entity::from_raw(0)));
+
+        assert_eq!(reconnect.expire(Duration::from_secs(5)), [b]);
+        interest.update(&[a], &reconnect, &groups, None, sources);
+        assert!(interest.area(b).is_none());
+    }
+
+    #[test]
+    fn the_filter_replaces_the_default_rule() {
+        let (a, b) = (user(1), user(2));
+        let filter = RelevanceFilter(Box::new(move |user, _, _, position: Vec3| user == a && position.x > 50.0));
+        let sources = [
+            (Entity::from_raw(0), source(None, true), Vec3::ZERO),
+            (Entity::from_raw(1), source(None, false), Vec3::X * 100.0),
+        ];
+        let sources = sources.iter().map(|(entity, source, position)| (*entity, source, *position));
+
+        let mut interest = NetInterest::default();
+        interest.update(&[a, b], &NetReconnect::default(), &NetGroups::default(), Some(&filter), sources);
+        assert_eq!(relevant(&interest, a), [Entity::from_raw(1)]);
+        assert!(relevant(&interest, b).is_empty());
+    }
+}
@@ -0,0 +1,55 @@
+//! Synchronization of members that join a session in progress.
+//!
+//! Messages registered with "MessageOptions::with_sticky" remember the last
+//! packet we broadcast. When a member joins, every peer sends it the last
+//! value of its sticky messages, ahead of any other queued packets.
+//!
+//! The host also receives an OnLateJoin event, to send the new member a
+//! snapshot of the replicated state. Systems sending the snapshot should run
+//! before the systems sending deltas, so the snapshot arrives first. Members
+//! returning within the reconnect grace period receive an OnReconnected event
+//! instead.
+
+use bevy::prelude::*;
+use crate::backends::{Backend, IBackend, OnLobbyChange, OnLobbyExit, UserId};
+use crate::context::NetContext;
+use crate::outbox::Outbox;
+use crate::reconnect::NetReconnect;
+use crate::relay::Router;
+
+/// The priority of sticky messages sent to a new member, so they are sent before other packets.
+const LATE_JOIN_PRIORITY: f32 = 1000.0;
+
+/// A member joined the lobby we are hosting, and needs a snapshot of the current state.
+#[derive(Event, Debug, Clone)]
+pub struct OnLateJoin {
+    pub user: UserId,
+}
+
+/// Send the last value of sticky messages to new members, and forget them when we leave.
+#[allow(clippy::too_many_arguments)]
+pub fn send_late_join(
+    context: Res<NetContext>,
+    backend: Res<Backend>,
+    router: Res<Router>,
+    outbox: Res<Outbox>,
+    reconnect: Res<NetReconnect>,
+    mut on_lobby_exit: EventReader<OnLobbyExit>,
+    mut on_lobby_change: EventReader<OnLobbyChange>,
+    mut on_late_join: EventWriter<OnLateJoin>,
+) {
+    if on_lobby_exit.read().count() > 0 {
+        context.messages.clear_sticky();
+    }
+
+    for ev in on_lobby_change.read() {
+        let OnLobbyChange::Joined(user) = ev else { continue };
+        for packet in context.messages.sticky() {
+            router.send(&backend, &outbox, *user, packet, LATE_JOIN_PRIORITY);
+        }
+
+        if backend.host_id() == Some(backend.user_id()) && !reconnect.is_held(*user) {
+            on_late_join.write(OnLateJoin { user: *user });
+        }
+    }
+}
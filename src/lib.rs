@@ -1,9 +1,9 @@
-use std::{collections::BTreeMap, io, marker::PhantomData, sync::Arc};
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use tokio::sync::mpsc;
-use xxhash_rust::const_xxh64::xxh64;
+use serde::{de::DeserializeOwned, Deserialize};
 use bevy::{log, prelude::*};
-use crate::{comms::{IncomingRx, OutgoingTx}, context::{Message, MessageType, NetContext}};
+use crate::{backends::{Backend, UserId}, context::{MessageOptions, NetContext, StableMessage}, error::RegistryError, session::SessionPolicy, limits::LimitAction, relay::Topology, interest::InterestArea};
+
+// lets the derive macros, which name the crate, be used inside it.
+extern crate self as bevy_skynet;
 
 pub mod prelude {
     pub type Client = crate::backends::Backend;
@@ -12,7 +12,23 @@ pub mod prelude {
         SkynetAppExt,
         SkynetConfig,
         SkynetPlugin,
-        params::{NetReceiver, NetSender},
+        NetMode,
+        outbox::Outbox,
+        params::{NetReceiver, NetSender, Packet},
+        relay::{Router, Topology},
+        session::{SessionPolicy, OnSessionRejected, SessionRejectReason},
+        auth::{AuthError, AuthSessions, OnAuthResult},
+        compression::Compression,
+        context::{Authority, Delivery, Message, MessageKey, MessageOptions, NetMessage, Overflow, StableMessage},
+        error::{NetError, RegistryError},
+        groups::NetGroups,
+        handshake::CompactIds,
+        health::{NetHealth, OnPeerHealth, PeerHealth},
+        interest::{InterestArea, InterestSource, NetInterest},
+        late_join::OnLateJoin,
+        reconnect::{NetReconnect, OnGraceExpired, OnReconnectFailed, OnReconnected},
+        limits::{LimitAction, RateLimiter},
+        transfer::{NetTransfer, OnTransferComplete, OnTransferFailed, OnTransferProgress, TransferDirection, TransferError, TransferId},
         backends::{
             Backend,
             OnLobbyChange,
@@ -34,21 +50,54 @@ pub mod prelude {
     };
 }
 
+pub mod auth;
 pub mod backends;
+pub mod compression;
 pub mod context;
+pub mod error;
+pub mod groups;
+pub mod handshake;
+pub mod health;
+pub(crate) mod header;
+pub mod interest;
+pub mod late_join;
+pub mod limits;
+pub mod outbox;
 pub mod params;
+pub mod reconnect;
+pub mod relay;
 pub mod comms;
+pub mod secure;
+pub mod session;
+pub mod transfer;
 pub mod util;
 
+/// Adds networking to the App. Whether the App runs as a client or as a
+/// dedicated server is determined by "general.mode" in Skynet.toml.
+/// 
+/// Works with both DefaultPlugins and MinimalPlugins. When used with
+/// MinimalPlugins, the StatesPlugin is added if it is not already present,
+/// so the SkynetPlugin must be added after any plugin group that includes it.
 pub struct SkynetPlugin;
 
 impl Plugin for SkynetPlugin {
     fn build(&self, app: &mut App) {
         use backends::*;
         let config = SkynetConfig::load_or_default();
+        let mode = config.general.mode;
+        let require_auth = config.general.require_auth;
+        let secure_channels = secure::SecureChannels::new(Backend::secure_channel(&config), Backend::secure_key(&config));
+        let limits = config.limits.clone();
+        let topology = config.general.topology;
+        let outbound = config.outbound.clone();
+
+        // Headless apps built on MinimalPlugins do not include states.
+        if !app.is_plugin_added::<bevy::state::app::StatesPlugin>() {
+            app.add_plugins(bevy::state::app::StatesPlugin);
+        }
     
         // Steamworks expects a steam_appid.txt to load, so we generate one from the config when in debug mode. 
-        #[cfg(feature = "steam")]
+        #[cfg(any(feature = "steam", feature = "steam_server"))]
         if cfg!(debug_assertions) {
             std::fs::write("steam_appid.txt", config.steamworks.app_id.to_string())
                 .expect("Failed to write steam_appid.txt");
@@ -63,13 +112,97 @@ impl Plugin for SkynetPlugin {
             .add_event::<OnLobbyMessage>()
             .add_event::<OnLobbyChange>()
             .add_event::<LobbyConnectError>()
+            .add_event::<session::OnSessionRejected>()
+            .add_event::<auth::OnAuthResult>()
+            .add_event::<error::NetError>()
+            .add_event::<health::OnPeerHealth>()
+            .add_event::<late_join::OnLateJoin>()
+            .add_event::<reconnect::OnReconnected>()
+            .add_event::<reconnect::OnGraceExpired>()
+            .add_event::<reconnect::OnReconnectFailed>()
+            .add_event::<transfer::OnTransferProgress>()
+            .add_event::<transfer::OnTransferComplete>()
+            .add_event::<transfer::OnTransferFailed>()
+            .init_resource::<session::PendingSessions>()
+            .init_resource::<groups::NetGroups>()
+            .init_resource::<handshake::CompactIds>()
+            .init_resource::<interest::NetInterest>()
+            .init_resource::<transfer::NetTransfer>()
+            .init_resource::<reconnect::NetReconnect>()
+            .init_resource::<health::NetHealth>()
+            .insert_resource(auth::AuthSessions::new(require_auth))
+            .insert_resource(secure_channels)
+            .insert_resource(limits::RateLimiter::new(limits))
+            .insert_resource(relay::Router::new(topology))
+            .insert_resource(outbox::Outbox::new(outbound))
+            .add_internal_message::<handshake::IdTable>(MessageOptions::default().with_priority(10.0))
+            .add_internal_message::<health::Heartbeat>(MessageOptions::default().with_priority(10.0))
+            .add_internal_message::<auth::AuthTicket>(MessageOptions::default().with_authority(context::Authority::ClientsToHost))
+            .add_internal_message::<transfer::TransferOffer>(MessageOptions::default())
+            .add_internal_message::<transfer::TransferChunk>(MessageOptions::default().with_priority(0.25))
+            .add_internal_message::<transfer::TransferAck>(MessageOptions::default())
+            .add_internal_message::<transfer::TransferCancel>(MessageOptions::default())
             .init_state::<LobbyState>()
             .init_state::<IsLobbyHost>()
+            .insert_state(mode)
+            .add_systems(
+                Startup,
+                backends::start_server
+                    .run_if(in_state(NetMode::Server))
+            )
+            .add_systems(PreUpdate, interest::update_interest)
             .add_systems(
                 Last, (
                     backends::read_backend_events,
+                    session::handle_session_requests
+                        .after(backends::read_backend_events),
+                    secure::update_secure_channels
+                        .after(backends::read_backend_events),
+                    auth::apply_auth_results
+                        .after(backends::read_backend_events),
+                    auth::send_auth_ticket
+                        .after(backends::read_backend_events),
+                    limits::update_rate_limits
+                        .after(reconnect::update_reconnect),
+                    groups::update_net_groups
+                        .after(reconnect::update_reconnect),
+                    outbox::update_outbox
+                        .after(backends::read_backend_events),
                     backends::recv_incoming_packets
+                        .after(session::handle_session_requests)
+                        .after(auth::apply_auth_results)
+                        .after(limits::update_rate_limits),
+                    auth::validate_auth_tickets
+                        .after(backends::recv_incoming_packets),
+                    context::send_net_errors
+                        .after(backends::recv_incoming_packets),
+                    handshake::send_id_tables
                         .after(backends::read_backend_events)
+                        .before(backends::recv_incoming_packets),
+                    handshake::recv_id_tables
+                        .after(backends::recv_incoming_packets),
+                    late_join::send_late_join
+                        .after(backends::read_backend_events),
+                    reconnect::update_reconnect
+                        .after(late_join::send_late_join),
+                    health::update_peer_health
+                        .after(backends::recv_incoming_packets),
+                    transfer::update_transfers
+                        .after(backends::read_backend_events),
+                    transfer::recv_transfers
+                        .after(transfer::update_transfers)
+                        .after(backends::recv_incoming_packets),
+                    transfer::send_transfers
+                        .after(transfer::recv_transfers),
+                    outbox::flush_outbox
+                        .after(outbox::update_outbox)
+                        .after(late_join::send_late_join)
+                        .after(health::update_peer_health)
+                        .after(handshake::send_id_tables)
+                        .after(handshake::recv_id_tables)
+                        .after(auth::send_auth_ticket)
+                        .after(auth::validate_auth_tickets)
+                        .after(transfer::send_transfers),
                 )
             )
         ;
@@ -80,6 +213,50 @@ pub trait SkynetAppExt {
     fn add_message<T>(&mut self) -> &mut Self
     where
         T: TypePath + DeserializeOwned + Send + Sync;
+
+    /// Register a message identified by a stable name rather than its type path.
+    fn add_message_named<T>(&mut self, name: &'static str) -> &mut Self
+    where
+        T: TypePath + DeserializeOwned + Send + Sync;
+
+    /// Register a message identified by an explicit ID rather than its type path.
+    fn add_message_with_id<T>(&mut self, id: u64) -> &mut Self
+    where
+        T: TypePath + DeserializeOwned + Send + Sync;
+
+    /// Register a message with the MessageKey it declares.
+    fn add_stable_message<T>(&mut self) -> &mut Self
+    where
+        T: StableMessage + TypePath + DeserializeOwned + Send + Sync;
+
+    /// Register a message with non-default options.
+    /// Messages registered with "MessageOptions::with_events" are read with an
+    /// EventReader<NetMessage<T>> or an observer, rather than a NetReceiver.
+    ///
+    /// Messages that fail to register, such as when their ID collides with one
+    /// that is already registered, are logged and not added.
+    fn add_message_with<T>(&mut self, options: MessageOptions) -> &mut Self
+    where
+        T: TypePath + DeserializeOwned + Send + Sync;
+
+    /// Register a message with non-default options, returning the error if it fails to register.
+    /// Messages added before the SkynetPlugin are registered at startup instead,
+    /// where their errors are logged.
+    fn try_add_message_with<T>(&mut self, options: MessageOptions) -> Result<&mut Self, RegistryError>
+    where
+        T: TypePath + DeserializeOwned + Send + Sync;
+
+    /// Set the filter used to accept or reject session requests
+    /// when the configured SessionPolicy is Custom.
+    fn set_session_filter<F>(&mut self, filter: F) -> &mut Self
+    where
+        F: Fn(UserId, &Backend) -> bool + Send + Sync + 'static;
+
+    /// Set the rule deciding whether an entity at a position is relevant to a peer,
+    /// replacing the default InterestArea and group rule.
+    fn set_relevance_filter<F>(&mut self, filter: F) -> &mut Self
+    where
+        F: Fn(UserId, Option<&InterestArea>, Entity, Vec3) -> bool + Send + Sync + 'static;
 }
 
 impl SkynetAppExt for App {
@@ -87,25 +264,112 @@ impl SkynetAppExt for App {
     where
         T: TypePath + DeserializeOwned + Send + Sync
     {
-        const SEED: u64 = 0x9e3779b185ebca87;
+        self.add_message_with::<T>(MessageOptions::default())
+    }
+
+    fn add_message_named<T>(&mut self, name: &'static str) -> &mut Self
+    where
+        T: TypePath + DeserializeOwned + Send + Sync
+    {
+        self.add_message_with::<T>(MessageOptions::default().with_name(name))
+    }
+
+    fn add_message_with_id<T>(&mut self, id: u64) -> &mut Self
+    where
+        T: TypePath + DeserializeOwned + Send + Sync
+    {
+        self.add_message_with::<T>(MessageOptions::default().with_id(id))
+    }
+
+    fn add_stable_message<T>(&mut self) -> &mut Self
+    where
+        T: StableMessage + TypePath + DeserializeOwned + Send + Sync
+    {
+        self.add_message_with::<T>(MessageOptions { key: T::KEY, ..default() })
+    }
+
+    fn add_message_with<T>(&mut self, options: MessageOptions) -> &mut Self
+    where
+        T: TypePath + DeserializeOwned + Send + Sync
+    {
+        if let Err(e) = add_message_to::<T>(self, options, false) {
+            log::error!("Failed to add Network Message: {e}");
+        }
+        self
+    }
+
+    fn try_add_message_with<T>(&mut self, options: MessageOptions) -> Result<&mut Self, RegistryError>
+    where
+        T: TypePath + DeserializeOwned + Send + Sync
+    {
+        add_message_to::<T>(self, options, false)?;
+        Ok(self)
+    }
+
+    fn set_session_filter<F>(&mut self, filter: F) -> &mut Self
+    where
+        F: Fn(UserId, &Backend) -> bool + Send + Sync + 'static
+    {
+        self.insert_resource(session::SessionFilter(Box::new(filter)))
+    }
+
+    fn set_relevance_filter<F>(&mut self, filter: F) -> &mut Self
+    where
+        F: Fn(UserId, Option<&InterestArea>, Entity, Vec3) -> bool + Send + Sync + 'static
+    {
+        self.insert_resource(interest::RelevanceFilter(Box::new(filter)))
+    }
+}
+
+/// Registration of the messages the plugin sends itself.
+trait InternalMessageExt {
+    fn add_internal_message<T>(&mut self, options: MessageOptions) -> &mut Self
+    where
+        T: TypePath + DeserializeOwned + Send + Sync;
+}
+
+impl InternalMessageExt for App {
+    fn add_internal_message<T>(&mut self, options: MessageOptions) -> &mut Self
+    where
+        T: TypePath + DeserializeOwned + Send + Sync
+    {
+        if let Err(e) = add_message_to::<T>(self, options, true) {
+            log::error!("Failed to add Network Message: {e}");
+        }
+        self
+    }
+}
 
-        self.add_systems(PreStartup,
+/// Register the message right away if the SkynetPlugin was added, so collisions
+/// are reported in the order messages are added, or at startup otherwise.
+fn add_message_to<T>(app: &mut App, options: MessageOptions, internal: bool) -> Result<(), RegistryError>
+where
+    T: TypePath + DeserializeOwned + Send + Sync
+{
+    let events = options.events;
+    let world = app.world_mut();
+    if world.contains_resource::<NetContext>() {
+        let registered = world.resource_scope(|world, ctx: Mut<NetContext>| {
+            let mut commands = world.commands();
+            ctx.register::<T>(&mut commands, options, internal)
+        });
+        world.flush();
+        registered?;
+    } else {
+        app.add_systems(PreStartup,
             move |ctx: Res<NetContext>, mut commands: Commands| {
-                let (tx, rx) = mpsc::channel::<Message<T>>(ctx.config.general.channel_size as usize);
-                let name = T::type_path();
-                let msg = Arc::new(
-                    MessageType {
-                        name,
-                        id: xxh64(name.as_bytes(), SEED),
-                        tx: Box::new(comms::IncomingTx { tx })
-                    }
-                );
-                ctx.messages.insert(msg.clone());
-                commands.insert_resource(OutgoingTx::<T> { message: msg, _marker: PhantomData });
-                commands.insert_resource(IncomingRx { rx, });
+                if let Err(e) = ctx.register::<T>(&mut commands, options.clone(), internal) {
+                    log::error!("Failed to add Network Message: {e}");
+                }
             }
-        )
+        );
     }
+
+    if events {
+        app.add_event::<context::NetMessage<T>>()
+            .add_systems(Last, context::send_message_events::<T>.after(backends::recv_incoming_packets));
+    }
+    Ok(())
 }
 
 #[derive(Deserialize, Default)]
@@ -113,6 +377,21 @@ pub struct SkynetConfig {
     #[serde(default)]
     pub general: GeneralConfig,
 
+    #[serde(default)]
+    pub server: ServerConfig,
+
+    #[serde(default)]
+    pub limits: LimitsConfig,
+
+    #[serde(default)]
+    pub outbound: OutboundConfig,
+
+    #[serde(default)]
+    pub transfer: TransferConfig,
+
+    #[serde(default)]
+    pub reconnect: ReconnectConfig,
+
     #[serde(default)]
     pub steamworks: SteamworksConfig,
 }
@@ -127,7 +406,7 @@ impl SkynetConfig {
                 .ok()
                 .and_then(|s| toml::from_str(&s).ok())
             {
-                Some(config) => config,
+                Some(config) => SkynetConfig::validated(config),
                 None => {
                     log::warn!("Failed to parse Skynet.toml, generating default config.");
                     SkynetConfig::default()
@@ -138,32 +417,423 @@ impl SkynetConfig {
             SkynetConfig::default()
         }
     }
+
+    /// Correct settings that are out of range, or cannot be used together.
+    fn validated(mut config: SkynetConfig) -> SkynetConfig {
+        let default = SkynetConfig::default();
+        let seconds = [
+            ("general.session_timeout", &mut config.general.session_timeout, default.general.session_timeout),
+            ("general.auth_timeout", &mut config.general.auth_timeout, default.general.auth_timeout),
+            ("general.heartbeat_interval", &mut config.general.heartbeat_interval, default.general.heartbeat_interval),
+            ("general.lag_threshold", &mut config.general.lag_threshold, default.general.lag_threshold),
+            ("general.peer_timeout", &mut config.general.peer_timeout, default.general.peer_timeout),
+            ("transfer.timeout", &mut config.transfer.timeout, default.transfer.timeout),
+            ("transfer.resume_timeout", &mut config.transfer.resume_timeout, default.transfer.resume_timeout),
+            ("reconnect.backoff", &mut config.reconnect.backoff, default.reconnect.backoff),
+            ("reconnect.max_backoff", &mut config.reconnect.max_backoff, default.reconnect.max_backoff),
+            ("reconnect.grace_period", &mut config.reconnect.grace_period, default.reconnect.grace_period),
+        ];
+        for (name, value, default) in seconds {
+            if std::time::Duration::try_from_secs_f32(*value).is_err() {
+                log::warn!("\"{name}\" must be a non-negative number of seconds, using '{default}'.");
+                *value = default;
+            }
+        }
+
+        // batched packets are framed with a 16-bit length.
+        let mtu = config.outbound.mtu.clamp(outbox::MIN_MTU, u16::MAX as u32);
+        if mtu != config.outbound.mtu {
+            log::warn!("\"outbound.mtu\" must be between '{}' and '{}', using '{mtu}'.", outbox::MIN_MTU, u16::MAX);
+            config.outbound.mtu = mtu;
+        }
+
+        let general = &mut config.general;
+        if general.mode == NetMode::Server && !cfg!(feature = "steam_server") {
+            log::error!("\"general.mode\" is Server, but the \"steam_server\" feature is not enabled, running as a Client.");
+            general.mode = NetMode::Client;
+        }
+
+        // the dedicated server backend replaces the client backend, so it cannot join lobbies.
+        if general.mode == NetMode::Client && cfg!(feature = "steam_server") {
+            log::error!("\"general.mode\" is Client, but the \"steam_server\" feature replaces the client backend, running as a Server.");
+            general.mode = NetMode::Server;
+        }
+
+        if general.mode == NetMode::Server && general.session_policy == SessionPolicy::Friends {
+            log::warn!("A dedicated server has no friends list, using the \"LobbyMembers\" session policy instead of \"Friends\".");
+            general.session_policy = SessionPolicy::LobbyMembers;
+        }
+
+        if general.require_auth && general.topology == Topology::Mesh {
+            // only the host validates tickets, so members must only hear from clients through it.
+            log::warn!("\"general.require_auth\" requires the Star topology, using Star instead of Mesh.");
+            general.topology = Topology::Star;
+        }
+        config
+    }
 }
 
 #[derive(Deserialize)]
+#[serde(default)]
 pub struct GeneralConfig {
-    #[serde(default)]
+    /// The number of unread messages held per message type, unless
+    /// set with "MessageOptions::with_capacity", and the size of event queues.
     pub channel_size: u32,
+
+    /// Whether to run as a client, or as a dedicated server.
+    /// Server requires the "steam_server" feature, and falls back to Client without it.
+    /// The feature replaces the client backend, so Client falls back to Server with it.
+    pub mode: NetMode,
+
+    /// Which users are allowed to open a P2P session with this user.
+    pub session_policy: SessionPolicy,
+
+    /// How long, in seconds, a session request from a user that is not
+    /// yet a lobby member is held before it is rejected.
+    pub session_timeout: f32,
+
+    /// Whether clients must send a valid authentication ticket to the host
+    /// before their messages are delivered.
+    pub require_auth: bool,
+
+    /// How long, in seconds, the host waits for a client's authentication ticket.
+    pub auth_timeout: f32,
+
+    /// Whether members send packets to each other directly, or through the host.
+    /// Always Star when "require_auth" is enabled.
+    pub topology: Topology,
+
+    /// How often, in seconds, a heartbeat is sent to each peer.
+    pub heartbeat_interval: f32,
+
+    /// How long, in seconds, a peer may be silent before it is Lagging.
+    pub lag_threshold: f32,
+
+    /// How long, in seconds, a peer may be silent before it is TimedOut.
+    pub peer_timeout: f32,
 }
 
 impl Default for GeneralConfig {
     fn default() -> Self {
         Self {
-            channel_size: 64
+            channel_size: 64,
+            mode: NetMode::Client,
+            session_policy: SessionPolicy::LobbyMembers,
+            session_timeout: 5.0,
+            require_auth: false,
+            auth_timeout: 10.0,
+            topology: Topology::Mesh,
+            heartbeat_interval: 1.0,
+            lag_threshold: 3.0,
+            peer_timeout: 10.0,
         }
     }
 }
 
+/// How the App participates in a session.
+#[derive(States, Deserialize, Copy, Clone, Eq, PartialEq, Debug, Hash, Default)]
+pub enum NetMode {
+    /// Join or host lobbies as a user.
+    #[default]
+    Client,
+
+    /// Run as a dedicated server without a user identity.
+    /// The server listens for clients on startup and is always the lobby host.
+    Server,
+}
+
+/// Settings used when running in NetMode::Server.
 #[derive(Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// The name of the server, as shown to clients.
+    pub name: String,
+
+    /// The maximum number of clients that may be connected at once, whatever the session policy.
+    pub max_clients: u32,
+
+    /// The port the server listens for clients on.
+    pub port: u16,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            name: "Skynet Server".into(),
+            max_clients: 16,
+            port: 27015,
+        }
+    }
+}
+
+/// Budgets for the packets received from each peer.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// The number of packets each peer may send per second. Zero means unlimited.
+    pub packets_per_sec: u32,
+
+    /// The number of bytes each peer may send per second. Zero means unlimited.
+    pub bytes_per_sec: u32,
+
+    /// How many seconds worth of budget a peer may use at once.
+    pub burst: f32,
+
+    /// The maximum number of packets processed per frame, across all peers.
+    /// Remaining packets are processed next frame. Zero means unlimited.
+    pub max_packets_per_frame: u32,
+
+    /// The maximum number of packets held per peer with LimitAction::Throttle.
+    pub max_backlog: u32,
+
+    /// What to do with packets from a peer that exceeded its budget.
+    pub action: LimitAction,
+
+    /// The largest size, in bytes, a compressed message may decompress to. Zero means unlimited.
+    pub max_decompressed_size: u32,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            packets_per_sec: 256,
+            bytes_per_sec: 256 * 1024,
+            burst: 1.0,
+            max_packets_per_frame: 1024,
+            max_backlog: 256,
+            action: LimitAction::Drop,
+            max_decompressed_size: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// Budgets for the packets sent to each peer.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct OutboundConfig {
+    /// The number of bytes sent to each peer per second. Packets over budget
+    /// are sent in later frames, in order of priority. Zero means unlimited.
+    pub bytes_per_sec: u32,
+
+    /// How many seconds worth of budget may be used at once.
+    pub burst: f32,
+
+    /// The size of the largest datagram small packets are batched into,
+    /// between 128 and 65535 bytes.
+    pub mtu: u32,
+}
+
+impl Default for OutboundConfig {
+    fn default() -> Self {
+        Self {
+            bytes_per_sec: 0,
+            burst: 1.0,
+            mtu: 1200,
+        }
+    }
+}
+
+/// Settings for blobs sent with the NetTransfer.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct TransferConfig {
+    /// The size, in bytes, of the chunks blobs are sent in.
+    pub chunk_size: u32,
+
+    /// The number of bytes sent to a user before waiting for acknowledgement.
+    pub window: u32,
+
+    /// How long, in seconds, to wait for the other side before a transfer fails.
+    pub timeout: f32,
+
+    /// The largest blob, in bytes, accepted from other users. Zero means unlimited.
+    pub max_size: u64,
+
+    /// The number of incoming transfers kept per user, including interrupted
+    /// ones waiting to be resumed. Zero means unlimited.
+    pub max_per_user: u32,
+
+    /// How long, in seconds, the data of an interrupted incoming transfer is kept.
+    pub resume_timeout: f32,
+}
+
+impl Default for TransferConfig {
+    fn default() -> Self {
+        Self {
+            chunk_size: 1024,
+            window: 64 * 1024,
+            timeout: 15.0,
+            max_size: 64 * 1024 * 1024,
+            max_per_user: 4,
+            resume_timeout: 300.0,
+        }
+    }
+}
+
+/// Settings for rejoining after the connection to the host was lost.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct ReconnectConfig {
+    /// The number of attempts to rejoin before giving up. Zero disables reconnection.
+    pub attempts: u32,
+
+    /// How long, in seconds, to wait before the first attempt. Doubles with every attempt.
+    pub backoff: f32,
+
+    /// The longest time, in seconds, to wait between attempts.
+    pub max_backoff: f32,
+
+    /// How long, in seconds, the host holds the slot of a member that left. Zero disables it.
+    pub grace_period: f32,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            attempts: 5,
+            backoff: 1.0,
+            max_backoff: 16.0,
+            grace_period: 30.0,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
 pub struct SteamworksConfig {
-    #[serde(default)]
     pub app_id: u32,
+
+    /// The port the dedicated server answers server browser queries on.
+    pub query_port: u16,
+
+    /// The version of the dedicated server. Clients with a different version
+    /// are shown as incompatible in the server browser.
+    pub server_version: String,
+
+    /// The product name of the dedicated server, usually the game's folder name.
+    pub product: String,
+
+    /// The description of the game shown in the server browser.
+    pub game_description: String,
+
+    /// The map name shown in the server browser.
+    pub map_name: String,
+
+    /// Comma-separated tags shown in the server browser.
+    pub game_tags: String,
+
+    /// Whether the dedicated server requires clients to be VAC secured.
+    pub vac_secure: bool,
+
+    /// Whether to encrypt packets with the SecureChannels. Steam already
+    /// relays packets over an encrypted transport, so this is usually unnecessary.
+    pub secure_channel: bool,
+
+    /// A secret shared by every peer, authenticating the secure channel's key exchange.
+    /// Without it, the key exchange is open to a man in the middle.
+    pub secure_key: String,
 }
 
 impl Default for SteamworksConfig {
     fn default() -> Self {
         Self {
-            app_id: 480
+            app_id: 480,
+            query_port: 27016,
+            server_version: "1.0.0.0".into(),
+            product: "bevy_skynet".into(),
+            game_description: String::new(),
+            map_name: String::new(),
+            game_tags: String::new(),
+            vac_secure: false,
+            secure_channel: false,
+            secure_key: String::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_seconds_fall_back_to_defaults() {
+        let mut config = SkynetConfig::default();
+        config.general.heartbeat_interval = -1.0;
+        config.general.peer_timeout = f32::NAN;
+        config.reconnect.max_backoff = f32::INFINITY;
+        config.transfer.timeout = f32::MAX;
+        config.general.lag_threshold = 0.5;
+
+        let config = SkynetConfig::validated(config);
+        let default = SkynetConfig::default();
+        assert_eq!(config.general.heartbeat_interval, default.general.heartbeat_interval);
+        assert_eq!(config.general.peer_timeout, default.general.peer_timeout);
+        assert_eq!(config.reconnect.max_backoff, default.reconnect.max_backoff);
+        assert_eq!(config.transfer.timeout, default.transfer.timeout);
+        assert_eq!(config.general.lag_threshold, 0.5);
+    }
+
+    #[test]
+    fn mtu_is_clamped() {
+        let mut config = SkynetConfig::default();
+        config.outbound.mtu = 100_000;
+        assert_eq!(SkynetConfig::validated(config).outbound.mtu, u16::MAX as u32);
+
+        let mut config = SkynetConfig::default();
+        config.outbound.mtu = 0;
+        assert_eq!(SkynetConfig::validated(config).outbound.mtu, outbox::MIN_MTU);
+    }
+
+    #[test]
+    fn mode_matches_the_backend() {
+        let expected = match cfg!(feature = "steam_server") {
+            true => NetMode::Server,
+            false => NetMode::Client,
+        };
+        for mode in [NetMode::Client, NetMode::Server] {
+            let mut config = SkynetConfig::default();
+            config.general.mode = mode;
+            assert_eq!(SkynetConfig::validated(config).general.mode, expected);
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn authentication_requires_star() {
+        let mut config = SkynetConfig::default();
+        config.general.require_auth = true;
+        assert_eq!(SkynetConfig::validated(config).general.topology, Topology::Star);
+    }
+
+    #[derive(Deserialize, TypePath)]
+    struct Ping;
+
+    #[derive(Deserialize, TypePath)]
+    struct Pong;
+
+    #[test]
+    fn colliding_messages_return_an_error() {
+        let mut app = App::new();
+        app.insert_resource(NetContext::new(SkynetConfig::default()));
+        assert!(app.try_add_message_with::<Ping>(MessageOptions::default().with_id(5)).is_ok());
+
+        let collision = app.try_add_message_with::<Pong>(MessageOptions::default().with_id(5));
+        assert!(matches!(collision, Err(RegistryError::Collision { .. })));
+        let duplicate = app.try_add_message_with::<Ping>(MessageOptions::default().with_id(6));
+        assert!(matches!(duplicate, Err(RegistryError::Duplicate { .. })));
+
+        // the app keeps running with the messages that were registered.
+        app.add_message_with::<Pong>(MessageOptions::default().with_id(5));
+        assert_eq!(app.world().resource::<NetContext>().messages.ids(), [5]);
+    }
+
+    #[test]
+    fn messages_added_before_the_plugin_are_registered_at_startup() {
+        let mut app = App::new();
+        app.add_message_with::<Ping>(MessageOptions::default().with_id(5));
+        app.insert_resource(NetContext::new(SkynetConfig::default()));
+        assert!(app.world().resource::<NetContext>().messages.ids().is_empty());
+
+        app.world_mut().run_schedule(PreStartup);
+        assert_eq!(app.world().resource::<NetContext>().messages.name_of(5), Some(Ping::type_path()));
+    }
+}
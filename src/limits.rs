@@ -0,0 +1,391 @@
+//! Per-peer rate limiting of incoming packets.
+//!
+//! Every peer has a packets/sec and a bytes/sec budget, configured in the
+//! "[limits]" section of Skynet.toml, and every message type may have a
+//! messages/sec quota set with "SkynetAppExt::add_message_with". Budgets are
+//! token buckets that hold up to "limits.burst" seconds worth of tokens.
+//!
+//! Packets that exceed a budget are handled according to "limits.action",
+//! and a NetError is sent the first time a peer exceeds its budget after
+//! having been within it.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::Duration;
+use bevy::prelude::*;
+use serde::Deserialize;
+use crate::backends::{OnLobbyChange, OnLobbyExit, UserId};
+use crate::context::MessageType;
+use crate::error::NetError;
+use crate::reconnect::{NetReconnect, OnGraceExpired};
+use crate::util::TokenBucket;
+use crate::LimitsConfig;
+
+/// What to do with packets from a peer that exceeded its budget.
+#[derive(Deserialize, Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub enum LimitAction {
+    /// Discard the packets.
+    #[default]
+    Drop,
+
+    /// Hold the packets, up to "limits.max_backlog" per peer, and deliver
+    /// them in order once the peer is within its budget again.
+    Throttle,
+
+    /// Close the session with the peer and discard everything it sends
+    /// until it leaves the lobby.
+    Kick,
+}
+
+impl std::fmt::Display for LimitAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use LimitAction::*;
+        f.write_str(match *self {
+            Drop => "Drop",
+            Throttle => "Throttle",
+            Kick => "Kick",
+        })
+    }
+}
+
+struct PeerLimits {
+    packets: TokenBucket,
+    bytes: TokenBucket,
+
+    /// Quotas of the message types received from this peer.
+    quotas: HashMap<u64, TokenBucket>,
+
+    /// Decrypted packets held while the peer is throttled.
+    backlog: VecDeque<Vec<u8>>,
+
+    /// Whether the peer exceeded its budget and has not recovered yet.
+    limited: bool,
+
+    /// When the buckets were last refilled.
+    refilled: Duration,
+}
+
+/// Rate limiting state of the peers we receive packets from.
+#[derive(Resource)]
+pub struct RateLimiter {
+    config: LimitsConfig,
+    peers: HashMap<UserId, PeerLimits>,
+
+    /// Peers that were kicked and have not left the lobby yet.
+    kicked: HashSet<UserId>,
+
+    /// The time of the current frame.
+    now: Duration,
+}
+
+impl RateLimiter {
+    pub fn new(config: LimitsConfig) -> Self {
+        Self {
+            config,
+            peers: HashMap::new(),
+            kicked: HashSet::new(),
+            now: Duration::ZERO,
+        }
+    }
+
+    /// The action taken when a peer exceeds its budget.
+    pub fn action(&self) -> LimitAction {
+        self.config.action
+    }
+
+    /// Whether the user was kicked for exceeding its budget.
+    pub fn is_kicked(&self, user: UserId) -> bool {
+        self.kicked.contains(&user)
+    }
+
+    /// The maximum number of packets processed per frame, or "usize::MAX" if unlimited.
+    pub(crate) fn frame_budget(&self) -> usize {
+        match self.config.max_packets_per_frame {
+            0 => usize::MAX,
+            n => n as usize,
+        }
+    }
+
+    /// Advance the clock used to refill the buckets.
+    pub(crate) fn tick(&mut self, now: Duration) {
+        self.now = now;
+    }
+
+    fn peer(&mut self, user: UserId) -> &mut PeerLimits {
+        let now = self.now;
+        let config = &self.config;
+        let peer = self.peers.entry(user).or_insert_with(|| PeerLimits {
+            packets: TokenBucket::new(config.packets_per_sec, config.burst),
+            bytes: TokenBucket::new(config.bytes_per_sec, config.burst),
+            quotas: HashMap::new(),
+            backlog: VecDeque::new(),
+            limited: false,
+            refilled: now,
+        });
+
+        let elapsed = now.saturating_sub(peer.refilled).as_secs_f32();
+        if elapsed > 0.0 {
+            peer.refilled = now;
+            peer.packets.refill(elapsed);
+            peer.bytes.refill(elapsed);
+            for quota in peer.quotas.values_mut() {
+                quota.refill(elapsed);
+            }
+        }
+        peer
+    }
+
+    /// Charge a packet to the peer's budgets. Nothing is charged if any budget is exceeded.
+    /// "message" is the registered type of the packet, if known.
+    pub(crate) fn check(&mut self, user: UserId, len: usize, message: Option<&MessageType>) -> Result<(), NetError> {
+        let action = self.config.action;
+        let burst = self.config.burst;
+        let peer = self.peer(user);
+        let len = len as f32;
+
+        if !peer.packets.can_take(1.0) || !peer.bytes.can_take(len) {
+            return Err(NetError::RateLimited { user, action });
+        }
+
+        let quota = match message {
+            Some(msg) if msg.options.quota != 0 => {
+                let quota = peer.quotas.entry(msg.id)
+                    .or_insert_with(|| TokenBucket::new(msg.options.quota, burst));
+                if !quota.can_take(1.0) {
+                    return Err(NetError::QuotaExceeded { user, message: msg.name, action });
+                }
+                Some(quota)
+            }
+            _ => None,
+        };
+
+        if let Some(quota) = quota {
+            quota.take(1.0);
+        }
+        peer.packets.take(1.0);
+        peer.bytes.take(len);
+        peer.limited = false;
+        Ok(())
+    }
+
+    /// Mark the peer as limited. Returns "true" if it was within its budget before,
+    /// in which case the violation should be reported.
+    pub(crate) fn report(&mut self, user: UserId) -> bool {
+        let peer = self.peer(user);
+        !std::mem::replace(&mut peer.limited, true)
+    }
+
+    /// Hold a packet until the peer is within its budget again.
+    /// Returns "false" if the backlog is full and the packet was discarded.
+    pub(crate) fn defer(&mut self, user: UserId, packet: Vec<u8>) -> bool {
+        let max = self.config.max_backlog as usize;
+        let peer = self.peer(user);
+        if peer.backlog.len() >= max {
+            return false;
+        }
+        peer.backlog.push_back(packet);
+        true
+    }
+
+    /// Whether the peer has packets waiting in its backlog.
+    pub(crate) fn is_throttled(&self, user: UserId) -> bool {
+        self.peers.get(&user).is_some_and(|peer| !peer.backlog.is_empty())
+    }
+
+    /// Peers with packets waiting in their backlog.
+    pub(crate) fn throttled(&self) -> Vec<UserId> {
+        self.peers.iter()
+            .filter(|(_, peer)| !peer.backlog.is_empty())
+            .map(|(user, _)| *user)
+            .collect()
+    }
+
+    pub(crate) fn front(&self, user: UserId) -> Option<&[u8]> {
+        self.peers.get(&user)?.backlog.front().map(Vec::as_slice)
+    }
+
+    pub(crate) fn pop_front(&mut self, user: UserId) -> Option<Vec<u8>> {
+        self.peers.get_mut(&user)?.backlog.pop_front()
+    }
+
+    /// Discard everything from the user until it leaves the lobby.
+    pub(crate) fn kick(&mut self, user: UserId) {
+        self.peers.remove(&user);
+        self.kicked.insert(user);
+    }
+
+    fn forget(&mut self, user: UserId) {
+        self.peers.remove(&user);
+        self.kicked.remove(&user);
+    }
+
+    /// Keep the budgets of a peer whose slot is held, so it cannot reset
+    /// them by reconnecting, but end its kick since it left the lobby.
+    fn hold(&mut self, user: UserId) {
+        self.kicked.remove(&user);
+    }
+
+    fn clear(&mut self) {
+        self.peers.clear();
+        self.kicked.clear();
+    }
+}
+
+/// Forget the budgets of peers that leave the lobby,
+/// or once their slot is released if it is held for them to reconnect.
+pub fn update_rate_limits(
+    reconnect: Res<NetReconnect>,
+    mut limiter: ResMut<RateLimiter>,
+    mut on_lobby_exit: EventReader<OnLobbyExit>,
+    mut on_lobby_change: EventReader<OnLobbyChange>,
+    mut on_grace_expired: EventReader<OnGraceExpired>,
+) {
+    if on_lobby_exit.read().count() > 0 {
+        limiter.clear();
+    }
+
+    for ev in on_lobby_change.read() {
+        match ev {
+            OnLobbyChange::Joined(_) => {}
+            OnLobbyChange::Exited(user) if reconnect.is_held(*user) => limiter.hold(*user),
+            OnLobbyChange::Exited(user)
+            | OnLobbyChange::Kicked { target: user, .. }
+            | OnLobbyChange::Banned { target: user, .. } => limiter.forget(*user),
+        }
+    }
+
+    for ev in on_grace_expired.read() {
+        limiter.forget(ev.user);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use super::*;
+    use crate::comms::{Inbox, IncomingTx};
+    use crate::context::{MessageOptions, Overflow};
+
+    fn user(id: u64) -> UserId {
+        UserId::from_raw(id)
+    }
+
+    fn app() -> App {
+        let config = LimitsConfig { packets_per_sec: 1, ..LimitsConfig::default() };
+        let mut app = App::new();
+        app.add_event::<OnLobbyExit>()
+            .add_event::<OnLobbyChange>()
+            .add_event::<OnGraceExpired>()
+            .insert_resource(RateLimiter::new(config))
+            .init_resource::<NetReconnect>()
+            .add_systems(Update, update_rate_limits);
+        app
+    }
+
+    fn check(app: &mut App, user: UserId) -> bool {
+        app.world_mut().resource_mut::<RateLimiter>().check(user, 10, None).is_ok()
+    }
+
+    #[test]
+    fn budgets_refill_over_time() {
+        let a = user(1);
+        let mut limiter = RateLimiter::new(LimitsConfig { packets_per_sec: 2, bytes_per_sec: 100, ..LimitsConfig::default() });
+        assert!(limiter.check(a, 40, None).is_ok());
+        assert!(limiter.check(a, 40, None).is_ok());
+        assert!(matches!(limiter.check(a, 1, None), Err(NetError::RateLimited { .. })));
+
+        // half a second refills one packet, but not 100 bytes.
+        limiter.tick(Duration::from_millis(500));
+        assert!(limiter.check(a, 100, None).is_err());
+        assert!(limiter.check(a, 40, None).is_ok());
+
+        // other peers have their own budgets.
+        assert!(limiter.check(user(2), 10, None).is_ok());
+    }
+
+    #[test]
+    fn quotas_are_per_message_type() {
+        let a = user(1);
+        let mut limiter = RateLimiter::new(LimitsConfig::default());
+        let message = |id| MessageType {
+            name: "test::Message",
+            id,
+            tx: Box::new(IncomingTx { inbox: Arc::new(Inbox::<()>::new(1, Overflow::DropNewest)) }),
+            options: MessageOptions::default().with_quota(1),
+            internal: false,
+            last: Default::default(),
+        };
+        let (first, second) = (message(1), message(2));
+        assert!(limiter.check(a, 10, Some(&first)).is_ok());
+        assert!(matches!(limiter.check(a, 10, Some(&first)), Err(NetError::QuotaExceeded { .. })));
+        assert!(limiter.check(a, 10, Some(&second)).is_ok());
+        assert!(limiter.check(a, 10, None).is_ok());
+    }
+
+    #[test]
+    fn violations_are_reported_once() {
+        let a = user(1);
+        let mut limiter = RateLimiter::new(LimitsConfig { packets_per_sec: 1, ..LimitsConfig::default() });
+        assert!(limiter.report(a));
+        assert!(!limiter.report(a));
+
+        // a packet within the budget ends the violation.
+        assert!(limiter.check(a, 10, None).is_ok());
+        assert!(limiter.report(a));
+    }
+
+    #[test]
+    fn throttled_packets_are_held_in_order() {
+        let a = user(1);
+        let mut limiter = RateLimiter::new(LimitsConfig { max_backlog: 2, ..LimitsConfig::default() });
+        assert!(limiter.defer(a, vec![1]));
+        assert!(limiter.defer(a, vec![2]));
+        assert!(!limiter.defer(a, vec![3]));
+        assert!(limiter.is_throttled(a));
+        assert_eq!(limiter.throttled(), [a]);
+
+        assert_eq!(limiter.front(a), Some(&[1][..]));
+        assert_eq!(limiter.pop_front(a), Some(vec![1]));
+        assert_eq!(limiter.pop_front(a), Some(vec![2]));
+        assert!(!limiter.is_throttled(a));
+
+        limiter.defer(a, vec![4]);
+        limiter.kick(a);
+        assert!(limiter.is_kicked(a));
+        assert!(!limiter.is_throttled(a));
+    }
+
+    #[test]
+    fn held_peers_keep_their_budgets_until_the_slot_is_released() {
+        let (a, b) = (user(1), user(2));
+        let mut app = app();
+        assert!(check(&mut app, a));
+        assert!(!check(&mut app, a));
+        assert!(check(&mut app, b));
+        app.world_mut().resource_mut::<RateLimiter>().kick(b);
+
+        // a's slot is held, so it cannot reset its budget by reconnecting.
+        app.world_mut().resource_mut::<NetReconnect>().hold(a, Duration::from_secs(5));
+        app.world_mut().send_event(OnLobbyChange::Exited(a));
+        app.world_mut().send_event(OnLobbyChange::Exited(b));
+        app.update();
+        assert!(!check(&mut app, a));
+        assert!(!app.world().resource::<RateLimiter>().is_kicked(b));
+
+        let expired = app.world_mut().resource_mut::<NetReconnect>().expire(Duration::from_secs(5));
+        assert_eq!(expired, [a]);
+        app.world_mut().send_event(OnGraceExpired { user: a });
+        app.update();
+        assert!(check(&mut app, a));
+    }
+
+    #[test]
+    fn a_held_peer_is_no_longer_kicked() {
+        let a = user(1);
+        let mut app = app();
+        app.world_mut().resource_mut::<RateLimiter>().kick(a);
+        app.world_mut().resource_mut::<NetReconnect>().hold(a, Duration::from_secs(5));
+        app.world_mut().send_event(OnLobbyChange::Exited(a));
+        app.update();
+        assert!(!app.world().resource::<RateLimiter>().is_kicked(a));
+    }
+}
@@ -0,0 +1,328 @@
+//! Outbound scheduling of packets.
+//!
+//! Packets sent with a NetSender are queued per peer and sent by "flush_outbox"
+//! at the end of the frame. Queued packets are sent in order of priority, within
+//! the per-peer budget configured in the "[outbound]" section of Skynet.toml.
+//! Packets that do not fit in the budget stay queued, and their priority grows
+//! every frame they wait, so low-priority messages are delayed but not starved.
+//! Packets of the same priority are always sent in the order they were queued.
+//!
+//! Headers are compacted for peers that confirmed their MessageRegistry matches
+//! ours, and restored if the peer stops accepting compact headers while queued.
+//!
+//! Packets to the same peer are batched into datagrams of up to "outbound.mtu"
+//! bytes, including the secure channel's overhead. Packets larger than the MTU
+//! are sent alone. Relayed packets are batched the same way.
+//!
+//! Wire format:
+//!  - Batch: [BATCH_ID header][2 byte big-endian length][packet][2 byte length][packet]...
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use bevy::prelude::*;
+use parking_lot::Mutex;
+use crate::backends::{Backend, OnLobbyChange, OnLobbyExit, UserId};
+use crate::context::{message_id, MessageRegistry, NetContext};
+use crate::handshake::CompactIds;
+use crate::header;
+use crate::secure::SecureChannels;
+use crate::util::TokenBucket;
+use crate::OutboundConfig;
+
+/// Reserved message ID of datagrams carrying several packets.
+pub(crate) const BATCH_ID: u64 = message_id("bevy_skynet::outbox::Batch");
+
+/// The smallest "outbound.mtu", leaving room for a batch of small packets after the secure channel's overhead.
+pub(crate) const MIN_MTU: u32 = 128;
+
+struct Queued {
+    /// The packet with a full header.
+    data: Arc<[u8]>,
+
+    /// The packet as it is sent, with a compact header if the peer accepts it.
+    wire: Arc<[u8]>,
+    priority: f32,
+
+    /// Grows by "priority" every frame the packet waits.
+    accumulated: f32,
+
+    /// Whether "wire" was prepared for a peer that accepts compact headers.
+    compacted: bool,
+}
+
+struct PeerQueue {
+    queue: Vec<Queued>,
+    budget: TokenBucket,
+
+    /// When the budget was last refilled.
+    refilled: Duration,
+}
+
+/// Packets waiting to be sent, per peer.
+#[derive(Resource)]
+pub struct Outbox {
+    config: OutboundConfig,
+    peers: Mutex<HashMap<UserId, PeerQueue>>,
+}
+
+impl Outbox {
+    pub fn new(config: OutboundConfig) -> Self {
+        Self {
+            config,
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The size of the largest datagram packets are batched into.
+    pub fn mtu(&self) -> usize {
+        self.config.mtu as usize
+    }
+
+    /// The number of bytes waiting to be sent to the user.
+    pub fn queued_bytes(&self, user: UserId) -> usize {
+        self.peers.lock().get(&user)
+            .map_or(0, |peer| peer.queue.iter().map(|q| q.wire.len()).sum())
+    }
+
+    /// Queue a packet to be sent to the user at the end of the frame.
+    pub(crate) fn push(&self, to: UserId, data: Arc<[u8]>, priority: f32) {
+        let mut peers = self.peers.lock();
+        let peer = peers.entry(to).or_insert_with(|| PeerQueue {
+            queue: Vec::new(),
+            budget: TokenBucket::new(self.config.bytes_per_sec, self.config.burst),
+            refilled: Duration::ZERO,
+        });
+        // a negative priority would let newer packets overtake older ones of the same priority.
+        let priority = priority.max(0.0);
+        peer.queue.push(Queued { wire: data.clone(), data, priority, accumulated: 0.0, compacted: false });
+    }
+
+    fn forget(&self, user: UserId) {
+        self.peers.lock().remove(&user);
+    }
+
+    fn clear(&self) {
+        self.peers.lock().clear();
+    }
+
+    /// Send as many queued packets as the budgets allow.
+    fn flush(&self, backend: &Backend, secure: &SecureChannels, registry: &MessageRegistry, compact: &CompactIds, now: Duration) {
+        let mtu = self.mtu().saturating_sub(secure.overhead());
+        let mut peers = self.peers.lock();
+        for (user, peer) in peers.iter_mut() {
+            let elapsed = now.saturating_sub(peer.refilled).as_secs_f32();
+            peer.refilled = now;
+            peer.budget.refill(elapsed);
+
+            let is_compact = compact.is_compact(*user);
+            for queued in &mut peer.queue {
+                queued.accumulated += queued.priority;
+                if is_compact != queued.compacted {
+                    queued.compacted = is_compact;
+                    queued.wire = match is_compact {
+                        true => header::compact(&queued.data, registry).map_or_else(|| queued.data.clone(), Into::into),
+                        false => queued.data.clone(),
+                    };
+                }
+            }
+            // stable, so packets of equal priority keep the order they were sent in.
+            peer.queue.sort_by(|a, b| b.accumulated.total_cmp(&a.accumulated));
+
+            let mut sent = 0;
+            for datagram in coalesce(&peer.queue, mtu) {
+                if !peer.budget.can_take(datagram.len as f32) {
+                    break;
+                }
+
+                peer.budget.take(datagram.len as f32);
+                let packets = &peer.queue[sent..sent + datagram.count];
+                if let [single] = packets {
+                    secure.send(backend, *user, &single.wire);
+                } else {
+                    secure.send(backend, *user, &batch(packets, datagram.len));
+                }
+                sent += datagram.count;
+            }
+            peer.queue.drain(..sent);
+        }
+    }
+}
+
+/// A run of consecutive queued packets sent as one datagram.
+struct Datagram {
+    count: usize,
+    len: usize,
+}
+
+/// Group consecutive packets into datagrams no larger than "mtu".
+/// Packets larger than the MTU are sent alone.
+fn coalesce(queue: &[Queued], mtu: usize) -> Vec<Datagram> {
+    let mut datagrams: Vec<Datagram> = Vec::new();
+    for queued in queue {
+        let len = queued.wire.len();
+        let batched = datagrams.last().map(|last| match last.count {
+            // the first packet of a datagram is framed once it has company.
+            1 => header::FULL_LEN + 2 + last.len + 2 + len,
+            _ => last.len + 2 + len,
+        });
+
+        match (datagrams.last_mut(), batched) {
+            (Some(last), Some(batched)) if batched <= mtu => {
+                last.count += 1;
+                last.len = batched;
+            }
+            _ => datagrams.push(Datagram { count: 1, len }),
+        }
+    }
+    datagrams
+}
+
+/// Write several packets into one datagram of "len" bytes.
+fn batch(packets: &[Queued], len: usize) -> Vec<u8> {
+    let mut batch = Vec::with_capacity(len);
+    header::write(&mut batch, BATCH_ID, 0);
+    for queued in packets {
+        batch.extend_from_slice(&(queued.wire.len() as u16).to_be_bytes());
+        batch.extend_from_slice(&queued.wire);
+    }
+    batch
+}
+
+/// Split a datagram into the packets coalesced into it.
+/// Returns "None" if the batch is malformed.
+pub(crate) fn unbatch(datagram: &[u8]) -> Option<Vec<&[u8]>> {
+    if header::is_compact(datagram) {
+        return Some(vec![datagram]);
+    }
+
+    let header = header::read(datagram)?;
+    if header.id != BATCH_ID {
+        return Some(vec![datagram]);
+    }
+
+    let mut packets = Vec::new();
+    let mut rest = &datagram[header.len..];
+    while !rest.is_empty() {
+        let len = u16::from_be_bytes(rest.get(..2)?.try_into().ok()?) as usize;
+        packets.push(rest.get(2..2 + len)?);
+        rest = &rest[2 + len..];
+    }
+    Some(packets)
+}
+
+/// Send the packets queued during the frame.
+pub fn flush_outbox(
+    context: Res<NetContext>,
+    backend: Res<Backend>,
+    secure: Res<SecureChannels>,
+    compact: Res<CompactIds>,
+    outbox: Res<Outbox>,
+    time: Res<Time<Real>>,
+) {
+    outbox.flush(&backend, &secure, &context.messages, &compact, time.elapsed());
+}
+
+/// Discard packets queued for peers that leave the lobby.
+pub fn update_outbox(
+    outbox: Res<Outbox>,
+    mut on_lobby_exit: EventReader<OnLobbyExit>,
+    mut on_lobby_change: EventReader<OnLobbyChange>,
+) {
+    if on_lobby_exit.read().count() > 0 {
+        outbox.clear();
+    }
+
+    for ev in on_lobby_change.read() {
+        match ev {
+            OnLobbyChange::Joined(_) => {}
+            OnLobbyChange::Exited(user)
+            | OnLobbyChange::Kicked { target: user, .. }
+            | OnLobbyChange::Banned { target: user, .. } => outbox.forget(*user),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(id: u64, len: usize) -> Queued {
+        let mut data = Vec::with_capacity(header::FULL_LEN + len);
+        header::write(&mut data, id, 0);
+        data.extend((0..len).map(|i| i as u8));
+        let data: Arc<[u8]> = data.into();
+        Queued { wire: data.clone(), data, priority: 1.0, accumulated: 0.0, compacted: false }
+    }
+
+    /// Coalesce the packets and split every datagram again.
+    fn round_trip(queue: &[Queued], mtu: usize) -> Vec<Vec<u8>> {
+        let mut received = Vec::new();
+        let mut sent = 0;
+        for datagram in coalesce(queue, mtu) {
+            let packets = &queue[sent..sent + datagram.count];
+            let bytes = match packets {
+                [single] => single.wire.to_vec(),
+                _ => batch(packets, datagram.len),
+            };
+            assert_eq!(bytes.len(), datagram.len);
+            assert!(datagram.count == 1 || bytes.len() <= mtu, "a batch exceeds the MTU");
+
+            received.extend(unbatch(&bytes).unwrap().into_iter().map(<[u8]>::to_vec));
+            sent += datagram.count;
+        }
+        assert_eq!(sent, queue.len());
+        received
+    }
+
+    fn sent(queue: &[Queued]) -> Vec<Vec<u8>> {
+        queue.iter().map(|queued| queued.wire.to_vec()).collect()
+    }
+
+    #[test]
+    fn small_packets_are_batched() {
+        let queue = (0..20).map(|i| packet(i, 10 + i as usize)).collect::<Vec<_>>();
+        assert!(coalesce(&queue, 200).len() < queue.len());
+        assert_eq!(round_trip(&queue, 200), sent(&queue));
+    }
+
+    #[test]
+    fn oversized_packets_are_sent_alone() {
+        let queue = vec![packet(1, 10), packet(2, 500), packet(3, 10), packet(4, 10)];
+        let datagrams = coalesce(&queue, 100);
+        assert_eq!(datagrams.iter().map(|d| d.count).collect::<Vec<_>>(), [1, 1, 2]);
+        assert_eq!(round_trip(&queue, 100), sent(&queue));
+    }
+
+    #[test]
+    fn packets_filling_the_mtu_are_not_batched() {
+        let queue = vec![packet(1, 100 - header::FULL_LEN), packet(2, 1)];
+        assert_eq!(coalesce(&queue, 100).len(), 2);
+        assert_eq!(round_trip(&queue, 100), sent(&queue));
+    }
+
+    #[test]
+    fn largest_mtu_fits_the_length_prefix() {
+        let len = u16::MAX as usize / 2 - 2 * header::FULL_LEN;
+        let queue = vec![packet(1, len), packet(2, len)];
+        assert_eq!(coalesce(&queue, u16::MAX as usize).len(), 1);
+        assert_eq!(round_trip(&queue, u16::MAX as usize), sent(&queue));
+    }
+
+    #[test]
+    fn empty_queue_and_batch() {
+        assert!(coalesce(&[], 100).is_empty());
+
+        let empty = batch(&[], header::FULL_LEN);
+        assert_eq!(unbatch(&empty), Some(Vec::new()));
+    }
+
+    #[test]
+    fn malformed_batches_are_rejected() {
+        let valid = batch(&[packet(1, 10), packet(2, 10)], 0);
+        assert!(unbatch(&valid).is_some());
+        assert!(unbatch(&valid[..valid.len() - 1]).is_none());
+        assert!(unbatch(&valid[..header::FULL_LEN + 1]).is_none());
+        assert!(unbatch(&valid[..4]).is_none());
+    }
+}
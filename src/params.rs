@@ -1,15 +1,19 @@
 
-use std::{io, marker::PhantomData};
+use std::{io, marker::PhantomData, sync::Arc};
 
-use bevy::{ecs::system::SystemParam, prelude::*};
+use bevy::{ecs::system::SystemParam, log, prelude::*};
 use serde::{de::DeserializeOwned, Serialize};
 
-use crate::{backends::{Backend, IBackend, UserId}, comms::{IncomingRx, OutgoingTx}, context::Message};
+use crate::{backends::{Backend, IBackend, UserId}, comms::{IncomingRx, OutgoingTx}, context::{Authority, Message, NetContext}, error::NetError, compression, groups::NetGroups, header, interest::NetInterest, outbox::Outbox, relay::Router};
 
 /// Receiver for network messages of a given type.
 /// Reading Network messages consumes them. Future reads
 /// of the receiver will not yield the message, even within 
 /// the same frame. 
+/// 
+/// Each type has its own receiver. The order in which a sender sent
+/// messages of different types, registered with the same priority,
+/// is restored by sorting them by "Message::seq".
 #[derive(SystemParam)]
 pub struct NetReceiver<'w, 's, T> 
 where
@@ -24,7 +28,7 @@ where
     T: DeserializeOwned + TypePath + Send + Sync + 'static
 {
     pub fn recv(&mut self) -> Option<Message<T>> {
-        self.rx.rx.try_recv().ok()
+        self.rx.inbox.pop()
     }
 }
 
@@ -46,32 +50,209 @@ pub struct NetSender<'w, 's, T>
 where
     T: Serialize + TypePath + Send + Sync + 'static
 {
+    context: Res<'w, NetContext>,
     backend: Res<'w, Backend>,
+    outbox: Res<'w, Outbox>,
+    router: Res<'w, Router>,
+    groups: Res<'w, NetGroups>,
+    interest: Res<'w, NetInterest>,
     tx: Res<'w, OutgoingTx<T>>,
     buf: Local<'s, Vec<u8>>,
 }
 
+/// A message encoded by "NetSender::encode", ready to be sent to any number
+/// of users, in this frame or later, without being serialized again.
+pub struct Packet<T> {
+    bytes: Arc<[u8]>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Packet<T> {
+    /// The encoded message, including its ID.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// The size of the encoded message in bytes.
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+}
+
+impl<T> Clone for Packet<T> {
+    fn clone(&self) -> Self {
+        Self {
+            bytes: self.bytes.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
 impl<'w, 's, T> NetSender<'w, 's, T>
 where
     T: Serialize + TypePath + Send + Sync + 'static
 {
     fn write_buffer(&mut self, message: &T) {
         self.buf.clear();
-        let msg_id = self.tx.message.id.to_be_bytes();
-        self.buf.extend_from_slice(&msg_id);
+        header::write(&mut self.buf, self.tx.message.id, 0);
         ciborium::into_writer(message, &mut*self.buf).unwrap();
+
+        let payload = &self.buf[header::FULL_LEN..];
+        let compressed = self.tx.message.options.compression.applies(payload.len())
+            .then(|| compression::compress(payload))
+            .flatten();
+
+        if let Some(compressed) = compressed {
+            self.buf.clear();
+            header::write(&mut self.buf, self.tx.message.id, header::COMPRESSED);
+            self.buf.extend_from_slice(&compressed);
+        }
+    }
+
+    /// Whether the message's Authority permits us to send it, reporting a NetError if not.
+    fn permits(&self, to: Option<UserId>) -> bool {
+        let user = self.backend.user_id();
+        let permits = self.tx.message.options.authority.permits(user, to, &self.backend);
+        if !permits {
+            self.context.report(NetError::Unauthorized { user, message: self.tx.message.name });
+        }
+        permits
+    }
+
+    fn send_bytes(&self, to: UserId, bytes: &Arc<[u8]>) {
+        if self.permits(Some(to)) {
+            let priority = self.tx.message.options.priority;
+            self.router.send(&self.backend, &self.outbox, to, bytes.clone(), priority);
+        }
+    }
+
+    fn broadcast_bytes(&self, bytes: &Arc<[u8]>) {
+        if let (Authority::ClientsToHost, Some(host)) = (&self.tx.message.options.authority, self.backend.host_id()) {
+            self.send_bytes(host, bytes);
+        } else if self.permits(None) {
+            self.tx.message.remember(bytes);
+            let priority = self.tx.message.options.priority;
+            self.router.broadcast(&self.backend, &self.outbox, bytes.clone(), priority);
+        }
+    }
+
+    /// Members of the lobby other than "except".
+    fn members_except(&self, except: UserId) -> Vec<UserId> {
+        self.backend.lobby_members().into_iter()
+            .filter(|user| *user != except)
+            .collect()
+    }
+
+    /// Members of the group other than ourselves.
+    fn group_members(&self, group: &str) -> Vec<UserId> {
+        let user = self.backend.user_id();
+        self.groups.members(group)
+            .filter(|member| *member != user)
+            .collect()
+    }
+
+    /// Members of the lobby the entity is relevant to.
+    fn relevant_members(&self, entity: Entity) -> Vec<UserId> {
+        self.backend.lobby_members().into_iter()
+            .filter(|user| self.interest.is_relevant(*user, entity))
+            .collect()
+    }
+
+    /// Encode a message once, so it can be sent to many users or cached across frames.
+    pub fn encode(&mut self, message: &T) -> Packet<T> {
+        self.write_buffer(message);
+        Packet {
+            bytes: Arc::from(self.buf.as_slice()),
+            _marker: PhantomData,
+        }
     }
 
     /// Broadcast a message to all connected users. 
+    /// Messages with Authority::ClientsToHost are sent to the host only.
     pub fn broadcast(&mut self, message: &T) {  
-        self.write_buffer(message);
-        self.backend.broadcast_packet(&self.buf);
+        let packet = self.encode(message);
+        self.broadcast_bytes(&packet.bytes);
     }
 
     /// Send a message to the user.
     pub fn send(&mut self, to: UserId, message: &T) {
-        self.write_buffer(message);
-        self.backend.send_packet(to, &self.buf);
+        let packet = self.encode(message);
+        self.send_bytes(to, &packet.bytes);
+    }
+
+    /// Send a message to the host of the lobby or server.
+    /// Does nothing if we are not connected to one, or if we are the host,
+    /// since messages are never delivered to their own sender.
+    pub fn send_to_host(&mut self, message: &T) {
+        match self.backend.host_id() {
+            Some(host) if host == self.backend.user_id() => {
+                log::debug!("Did not send '{}' to the host, because we are the host.", self.tx.message.name);
+            }
+            Some(host) => self.send(host, message),
+            None => {}
+        }
     }
-}
 
+    /// Send a message to each of the users, serializing it only once.
+    pub fn send_many(&mut self, to: &[UserId], message: &T) {
+        let packet = self.encode(message);
+        self.send_packet_many(to, &packet);
+    }
+
+    /// Broadcast a message to all connected users except one,
+    /// such as the user whose input is being relayed.
+    pub fn broadcast_except(&mut self, except: UserId, message: &T) {
+        let members = self.members_except(except);
+        self.send_many(&members, message);
+    }
+
+    /// Send a message to every member of the group, except ourselves.
+    pub fn send_group(&mut self, group: &str, message: &T) {
+        let members = self.group_members(group);
+        self.send_many(&members, message);
+    }
+
+    /// Send an update about the entity to the members it is relevant to,
+    /// according to the NetInterest. Interest management is opt-in, so the other
+    /// methods send to every recipient regardless of it.
+    pub fn send_interested(&mut self, entity: Entity, message: &T) {
+        let members = self.relevant_members(entity);
+        self.send_many(&members, message);
+    }
+
+    /// Broadcast an encoded message to all connected users.
+    pub fn broadcast_packet(&self, packet: &Packet<T>) {
+        self.broadcast_bytes(&packet.bytes);
+    }
+
+    /// Send an encoded message to the user.
+    pub fn send_packet(&self, to: UserId, packet: &Packet<T>) {
+        self.send_bytes(to, &packet.bytes);
+    }
+
+    /// Send an encoded message to each of the users.
+    pub fn send_packet_many(&self, to: &[UserId], packet: &Packet<T>) {
+        for &user in to {
+            self.send_bytes(user, &packet.bytes);
+        }
+    }
+
+    /// Broadcast an encoded message to all connected users except one.
+    pub fn broadcast_packet_except(&self, except: UserId, packet: &Packet<T>) {
+        self.send_packet_many(&self.members_except(except), packet);
+    }
+
+    /// Send an encoded message to every member of the group, except ourselves.
+    pub fn send_packet_group(&self, group: &str, packet: &Packet<T>) {
+        self.send_packet_many(&self.group_members(group), packet);
+    }
+
+    /// Send an encoded update about the entity to the members it is relevant to.
+    pub fn send_packet_interested(&self, entity: Entity, packet: &Packet<T>) {
+        self.send_packet_many(&self.relevant_members(entity), packet);
+    }
+}
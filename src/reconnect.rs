@@ -0,0 +1,213 @@
+//! Reconnection of members whose connection to the host was lost.
+//!
+//! When the connection drops, the client retries joining the same lobby or
+//! server, waiting "reconnect.backoff" seconds before the first attempt and
+//! twice as long before each following one, up to "reconnect.max_backoff".
+//!
+//! The host holds the slot of a member that left for "reconnect.grace_period"
+//! seconds. If the member returns in time, both sides receive an OnReconnected
+//! event instead of the member being treated as a new player, and the member
+//! is sent the last value of sticky messages as a resync. Otherwise, the host
+//! receives an OnGraceExpired event, and the player can be removed for good.
+//! Groups, rate limits and interest areas of a member are kept while its slot
+//! is held, and forgotten once it is released.
+
+use std::collections::HashMap;
+use std::time::Duration;
+use bevy::prelude::*;
+use bevy::log;
+use crate::backends::{Backend, IBackend, LobbyState, OnLobbyChange, OnLobbyExit, OnLobbyJoin, UserId};
+use crate::context::NetContext;
+
+/// A member returned within the grace period. On the host, "user" is the member that
+/// returned. On the member that reconnected, "user" is ourselves.
+#[derive(Event, Debug, Clone)]
+pub struct OnReconnected {
+    pub user: UserId,
+}
+
+/// A member did not return within the grace period, and its slot was released.
+#[derive(Event, Debug, Clone)]
+pub struct OnGraceExpired {
+    pub user: UserId,
+}
+
+/// Every attempt to reconnect after the connection was lost failed.
+#[derive(Event, Debug, Clone)]
+pub struct OnReconnectFailed {
+    /// The number of attempts made.
+    pub attempts: u32,
+}
+
+/// An attempt to rejoin after the connection was lost.
+struct Attempt {
+    count: u32,
+
+    /// When the next attempt is made.
+    next: Duration,
+}
+
+/// Tracks our attempts to reconnect, and, as the host, the slots of members that left.
+#[derive(Resource, Default)]
+pub struct NetReconnect {
+    attempt: Option<Attempt>,
+
+    /// Members that left, and when their slot is released.
+    held: HashMap<UserId, Duration>,
+}
+
+impl NetReconnect {
+    /// Whether we are trying to rejoin after the connection was lost.
+    pub fn is_reconnecting(&self) -> bool {
+        self.attempt.is_some()
+    }
+
+    /// Whether the host is holding the slot of a member that left.
+    pub fn is_held(&self, user: UserId) -> bool {
+        self.held.contains_key(&user)
+    }
+
+    /// Stop trying to rejoin.
+    pub fn cancel(&mut self) {
+        self.attempt = None;
+    }
+
+    /// Hold the slot of a member that left until "until".
+    pub(crate) fn hold(&mut self, user: UserId, until: Duration) {
+        self.held.insert(user, until);
+    }
+
+    /// Release the slot of a member, returning whether it was held.
+    pub(crate) fn release(&mut self, user: UserId) -> bool {
+        self.held.remove(&user).is_some()
+    }
+
+    /// Release the slots held past their grace period, returning their members.
+    pub(crate) fn expire(&mut self, now: Duration) -> Vec<UserId> {
+        let expired = self.held.iter()
+            .filter(|(_, until)| now >= **until)
+            .map(|(user, _)| *user)
+            .collect::<Vec<_>>();
+        for user in &expired {
+            self.held.remove(user);
+        }
+        expired
+    }
+}
+
+/// Retry dropped connections with backoff, and hold the slots of members that leave.
+#[allow(clippy::too_many_arguments)]
+pub fn update_reconnect(
+    context: Res<NetContext>,
+    backend: Res<Backend>,
+    time: Res<Time<Real>>,
+    mut reconnect: ResMut<NetReconnect>,
+    mut on_lobby_join: EventReader<OnLobbyJoin>,
+    mut on_lobby_exit: EventReader<OnLobbyExit>,
+    mut on_lobby_change: EventReader<OnLobbyChange>,
+    mut on_reconnected: EventWriter<OnReconnected>,
+    mut on_grace_expired: EventWriter<OnGraceExpired>,
+    mut on_failed: EventWriter<OnReconnectFailed>,
+) {
+    let now = time.elapsed();
+    let config = &context.config.reconnect;
+    let backoff = |count: u32| {
+        let secs = config.backoff * 2f32.powi(count.min(16) as i32);
+        Duration::from_secs_f32(secs.min(config.max_backoff))
+    };
+
+    for ev in on_lobby_exit.read() {
+        reconnect.held.clear();
+        reconnect.attempt = (ev.dropped && config.attempts > 0).then(|| {
+            log::warn!("The connection to the lobby was lost, reconnecting.");
+            Attempt { count: 0, next: now + backoff(0) }
+        });
+    }
+
+    if on_lobby_join.read().count() > 0 && reconnect.attempt.take().is_some() {
+        log::info!("Reconnected to the lobby.");
+        on_reconnected.write(OnReconnected { user: backend.user_id() });
+    }
+
+    let is_host = backend.host_id() == Some(backend.user_id());
+    let grace = Duration::from_secs_f32(config.grace_period);
+    for ev in on_lobby_change.read() {
+        match ev {
+            OnLobbyChange::Joined(user) => if reconnect.release(*user) {
+                log::info!("UserID '{:?}' reconnected.", user);
+                on_reconnected.write(OnReconnected { user: *user });
+            }
+            OnLobbyChange::Exited(user) => if is_host && !grace.is_zero() {
+                reconnect.hold(*user, now + grace);
+            }
+            OnLobbyChange::Kicked { target, .. }
+            | OnLobbyChange::Banned { target, .. } => {
+                reconnect.release(*target);
+            }
+        }
+    }
+
+    for user in reconnect.expire(now) {
+        on_grace_expired.write(OnGraceExpired { user });
+    }
+
+    let Some(attempt) = reconnect.attempt.as_mut() else { return };
+    if now < attempt.next || backend.lobby_state() != LobbyState::None {
+        return;
+    }
+
+    if attempt.count >= config.attempts {
+        log::warn!("Failed to reconnect to the lobby after '{}' attempts.", attempt.count);
+        on_failed.write(OnReconnectFailed { attempts: attempt.count });
+        reconnect.attempt = None;
+        return;
+    }
+
+    attempt.count += 1;
+    attempt.next = now + backoff(attempt.count);
+    log::info!("Reconnecting to the lobby (attempt: '{}')", attempt.count);
+    if !backend.reconnect() {
+        reconnect.attempt = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(id: u64) -> UserId {
+        UserId::from_raw(id)
+    }
+
+    #[test]
+    fn held_slots_are_resumed_or_expire() {
+        let (a, b) = (user(1), user(2));
+        let mut reconnect = NetReconnect::default();
+        reconnect.hold(a, Duration::from_secs(5));
+        reconnect.hold(b, Duration::from_secs(10));
+        assert!(reconnect.is_held(a) && reconnect.is_held(b));
+
+        // a returns in time.
+        assert!(reconnect.release(a));
+        assert!(!reconnect.is_held(a));
+        assert!(!reconnect.release(a));
+
+        assert!(reconnect.expire(Duration::from_secs(9)).is_empty());
+        assert!(reconnect.is_held(b));
+        assert_eq!(reconnect.expire(Duration::from_secs(10)), [b]);
+        assert!(!reconnect.is_held(b));
+        assert!(reconnect.expire(Duration::from_secs(20)).is_empty());
+    }
+
+    #[test]
+    fn leaving_again_restarts_the_grace_period() {
+        let a = user(1);
+        let mut reconnect = NetReconnect::default();
+        reconnect.hold(a, Duration::from_secs(5));
+        assert!(reconnect.release(a));
+        reconnect.hold(a, Duration::from_secs(12));
+
+        assert!(reconnect.expire(Duration::from_secs(6)).is_empty());
+        assert_eq!(reconnect.expire(Duration::from_secs(12)), [a]);
+    }
+}
@@ -0,0 +1,222 @@
+//! Star topology, where clients only exchange packets with the host.
+//!
+//! When "general.topology" is Star, packets a client sends to another member
+//! are wrapped in a Relay envelope and sent to the host, which forwards them in
+//! a Forward envelope carrying the original sender. Clients only accept Forward
+//! envelopes from the host, and the host only relays packets as their actual
+//! sender, so the sender of a relayed Message cannot be forged. The host only
+//! relays packets to members of its lobby.
+//!
+//! Wire format, following the secure channel:
+//!  - Relay:   [RELAY_ID header][8 byte target, 0 to broadcast][packet]
+//!  - Forward: [FORWARD_ID header][8 byte original sender][packet]
+//!
+//! The relayed packet always keeps its full header, since the registries of
+//! the sender and the final receiver are not compared.
+
+use std::sync::Arc;
+use bevy::prelude::*;
+use serde::Deserialize;
+use crate::backends::{Backend, IBackend, UserId};
+use crate::context::message_id;
+use crate::header::{self, Header};
+use crate::outbox::Outbox;
+
+/// Reserved message ID of packets a client asks the host to relay.
+pub(crate) const RELAY_ID: u64 = message_id("bevy_skynet::relay::Relay");
+
+/// Reserved message ID of packets the host relayed.
+pub(crate) const FORWARD_ID: u64 = message_id("bevy_skynet::relay::Forward");
+
+/// How packets travel between the members of a lobby.
+#[derive(Deserialize, Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub enum Topology {
+    /// Every member sends packets directly to every other member.
+    #[default]
+    Mesh,
+
+    /// Clients only send packets to the host, which relays them to other members.
+    Star,
+}
+
+/// Where a received envelope should go.
+pub(crate) enum Route<'a> {
+    /// A packet that was not relayed.
+    Direct(&'a [u8]),
+
+    /// A client asked the host to relay a packet. "None" broadcasts it.
+    Relay { target: Option<UserId>, packet: &'a [u8] },
+
+    /// The host relayed a packet from the original sender.
+    Forward { origin: UserId, packet: &'a [u8] },
+}
+
+/// Routes packets through the host when the Topology is Star.
+#[derive(Resource)]
+pub struct Router {
+    topology: Topology,
+}
+
+impl Router {
+    pub fn new(topology: Topology) -> Self {
+        Self { topology }
+    }
+
+    pub fn topology(&self) -> Topology {
+        self.topology
+    }
+
+    /// Whether packets to the user are sent to it directly.
+    /// Returns the host to relay through otherwise.
+    pub(crate) fn relay_for(&self, backend: &Backend, to: Option<UserId>) -> Option<UserId> {
+        relay_through(self.topology, backend.host_id(), backend.user_id(), to)
+    }
+
+    /// Whether a session with the user is needed with the current Topology.
+    pub(crate) fn is_peer(&self, backend: &Backend, user: UserId) -> bool {
+        self.relay_for(backend, Some(user)).is_none()
+    }
+
+    /// Whether the user is the host we relay packets through.
+    pub(crate) fn is_relay(&self, backend: &Backend, user: UserId) -> bool {
+        self.topology == Topology::Star
+            && backend.host_id() == Some(user)
+            && user != backend.user_id()
+    }
+
+    /// Queue a packet to the user, through the host if needed.
+    pub(crate) fn send(&self, backend: &Backend, outbox: &Outbox, to: UserId, data: Arc<[u8]>, priority: f32) {
+        match self.relay_for(backend, Some(to)) {
+            None => outbox.push(to, data, priority),
+            Some(host) => {
+                let packet = envelope(RELAY_ID, backend.encode_user_id(to), &data);
+                outbox.push(host, packet.into(), priority);
+            }
+        }
+    }
+
+    /// Queue a packet to all members, through the host if needed.
+    pub(crate) fn broadcast(&self, backend: &Backend, outbox: &Outbox, data: Arc<[u8]>, priority: f32) {
+        match self.relay_for(backend, None) {
+            None => {
+                for member in backend.lobby_members() {
+                    outbox.push(member, data.clone(), priority);
+                }
+            }
+            Some(host) => outbox.push(host, envelope(RELAY_ID, 0, &data).into(), priority),
+        }
+    }
+
+    /// Queue a packet relayed by the host to the user, so it is batched
+    /// with the other packets to that user.
+    pub(crate) fn forward(&self, backend: &Backend, outbox: &Outbox, origin: UserId, to: UserId, data: &[u8], priority: f32) {
+        let packet = envelope(FORWARD_ID, backend.encode_user_id(origin), data);
+        outbox.push(to, packet.into(), priority);
+    }
+
+    /// Unwrap an envelope. Returns "None" if the envelope is malformed.
+    pub(crate) fn route<'a>(&self, backend: &Backend, header: Header, packet: &'a [u8]) -> Option<Route<'a>> {
+        if header.id != RELAY_ID && header.id != FORWARD_ID {
+            return Some(Route::Direct(packet));
+        }
+
+        let (raw, inner) = open(header, packet)?;
+        if header.id == RELAY_ID {
+            let target = (raw != 0).then(|| backend.decode_user_id(raw));
+            Some(Route::Relay { target, packet: inner })
+        } else {
+            Some(Route::Forward { origin: backend.decode_user_id(raw), packet: inner })
+        }
+    }
+}
+
+/// The host to relay a packet to "to" through, or "None" to send it directly.
+/// "None" as "to" is a broadcast.
+fn relay_through(topology: Topology, host: Option<UserId>, us: UserId, to: Option<UserId>) -> Option<UserId> {
+    if topology == Topology::Mesh {
+        return None;
+    }
+
+    let host = host?;
+    if host == us || to == Some(host) {
+        None
+    } else {
+        Some(host)
+    }
+}
+
+fn envelope(id: u64, user: u64, data: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(header::FULL_LEN + 8 + data.len());
+    header::write(&mut packet, id, 0);
+    packet.extend_from_slice(&user.to_be_bytes());
+    packet.extend_from_slice(data);
+    packet
+}
+
+/// The user and the packet inside an envelope. Returns "None" if the envelope is malformed.
+fn open(header: Header, packet: &[u8]) -> Option<(u64, &[u8])> {
+    if packet.len() <= header.len + 8 {
+        return None;
+    }
+
+    let raw = u64::from_be_bytes(packet[header.len..header.len + 8].try_into().ok()?);
+    Some((raw, &packet[header.len + 8..]))
+}
+
+/// The message ID of the packet, or of the packet inside it if it is an envelope.
+pub(crate) fn inner_msg_id(packet: &[u8]) -> Option<u64> {
+    let outer = header::read(packet)?;
+    match outer.id {
+        RELAY_ID | FORWARD_ID => header::read(packet.get(outer.len + 8..)?).map(|inner| inner.id),
+        msg_id => Some(msg_id),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(id: u64) -> UserId {
+        UserId::from_raw(id)
+    }
+
+    fn packet(id: u64) -> Vec<u8> {
+        let mut packet = Vec::new();
+        header::write(&mut packet, id, 0);
+        packet.extend_from_slice(b"payload");
+        packet
+    }
+
+    #[test]
+    fn clients_relay_through_the_host_with_star() {
+        let (host, a, b) = (user(1), user(2), user(3));
+        assert_eq!(relay_through(Topology::Star, Some(host), a, Some(b)), Some(host));
+        assert_eq!(relay_through(Topology::Star, Some(host), a, None), Some(host));
+        assert_eq!(relay_through(Topology::Star, Some(host), a, Some(host)), None);
+        // the host sends directly, and nothing is relayed without a host.
+        assert_eq!(relay_through(Topology::Star, Some(host), host, Some(a)), None);
+        assert_eq!(relay_through(Topology::Star, None, a, Some(b)), None);
+        assert_eq!(relay_through(Topology::Mesh, Some(host), a, Some(b)), None);
+    }
+
+    #[test]
+    fn envelopes_wrap_the_full_packet() {
+        let inner = packet(42);
+        let relayed = envelope(RELAY_ID, 7, &inner);
+        let header = header::read(&relayed).unwrap();
+        assert_eq!(header.id, RELAY_ID);
+        assert_eq!(open(header, &relayed), Some((7, inner.as_slice())));
+        assert_eq!(inner_msg_id(&relayed), Some(42));
+        assert_eq!(inner_msg_id(&envelope(FORWARD_ID, 7, &inner)), Some(42));
+        assert_eq!(inner_msg_id(&inner), Some(42));
+    }
+
+    #[test]
+    fn malformed_envelopes_are_rejected() {
+        let relayed = envelope(RELAY_ID, 7, &[]);
+        let header = header::read(&relayed).unwrap();
+        assert_eq!(open(header, &relayed), None);
+        assert_eq!(open(header, &relayed[..header.len + 4]), None);
+        assert_eq!(inner_msg_id(&envelope(FORWARD_ID, 7, &[1, 2])), None);
+    }
+}
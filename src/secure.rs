@@ -0,0 +1,735 @@
+//! Optional secure channel below the NetSender and recv_incoming_packets.
+//!
+//! Steam relays traffic over an encrypted transport, but other transports may
+//! not, so a backend can enable this layer in Skynet.toml. When enabled, peers
+//! exchange X25519 public keys on connect and every packet is encrypted and
+//! authenticated with ChaCha20-Poly1305 under a key derived from the shared secret.
+//!
+//! The key exchange itself is only authenticated when the backend configures a
+//! pre-shared key, such as "steamworks.secure_key". Every Hello then carries a
+//! tag derived from it, and it is mixed into the session key, so a man in the
+//! middle that does not know it can neither substitute its own public keys nor
+//! read the packets. Without a pre-shared key, packets are protected from
+//! passive eavesdroppers and from tampering, but not from an active attacker
+//! that intercepts the key exchange.
+//!
+//! Every key exchange uses fresh keys and a random nonce on both sides, which
+//! are covered by the tag and mixed into the session key. A Hello answering
+//! another echoes the public key it answers, so the sender of that key completes
+//! the exchange with it rather than starting another. Public keys received once
+//! are never accepted again, so a replayed Hello cannot bring back an old session
+//! key and reset its counters.
+//!
+//! A peer may replace its keys, for example after restarting, but at most once
+//! every REKEY_INTERVAL. Hellos arriving sooner are deferred until it elapses,
+//! keeping only the latest.
+//!
+//! With a Star topology, clients only exchange keys with the host, and relayed
+//! packets are encrypted separately on each hop.
+//!
+//! Packets sent to a peer before the key exchange completes are queued and sent
+//! once the peer's public key arrives.
+//!
+//! Wire format:
+//!  - Hello:  [0][32 byte public key][16 byte nonce][32 byte echoed public key, or zeros][32 byte tag]
+//!  - Sealed: [1][8 byte big-endian counter][ciphertext + 16 byte tag]
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use bevy::prelude::*;
+use bevy::log;
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, Key, KeyInit, Nonce};
+use parking_lot::Mutex;
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+use crate::backends::{Backend, IBackend, OnLobbyChange, OnLobbyExit, OnLobbyJoin, UserId};
+use crate::relay::Router;
+
+const HELLO: u8 = 0;
+const SEALED: u8 = 1;
+
+/// The size of a Hello, after its first byte.
+const HELLO_LEN: usize = 32 + 16 + 32 + 32;
+
+/// Domain separation for key derivation.
+const KDF_CONTEXT: &[u8] = b"bevy_skynet secure channel v2";
+
+/// Domain separation for the tags of Hellos.
+const HELLO_CONTEXT: &[u8] = b"bevy_skynet secure hello v2";
+
+/// The minimum time between two key exchanges with the same peer.
+pub const REKEY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Per-peer encryption state, shared by the NetSender and recv_incoming_packets.
+#[derive(Resource)]
+pub struct SecureChannels {
+    enabled: bool,
+
+    /// Derived from the backend's pre-shared key, if one is configured.
+    psk: Option<[u8; 32]>,
+    peers: Mutex<HashMap<UserId, SecurePeer>>,
+
+    /// Every public key used in a key exchange, which are never accepted again.
+    seen: Mutex<HashSet<[u8; 32]>>,
+}
+
+/// Our keys for a key exchange.
+struct Ephemeral {
+    secret: StaticSecret,
+    public: PublicKey,
+    nonce: [u8; 16],
+
+    /// Whether a session key was derived from them.
+    used: bool,
+
+    /// Whether they were sent to the peer.
+    sent: bool,
+}
+
+impl Ephemeral {
+    fn new() -> Self {
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        let mut nonce = [0; 16];
+        OsRng.fill_bytes(&mut nonce);
+        Self { secret, public, nonce, used: false, sent: false }
+    }
+}
+
+/// A Hello whose tag was verified.
+#[derive(Copy, Clone)]
+struct Hello {
+    public: PublicKey,
+    nonce: [u8; 16],
+
+    /// The public key the Hello answers.
+    echo: [u8; 32],
+}
+
+/// What to do with a received Hello.
+#[derive(Debug, PartialEq)]
+enum HelloAction {
+    /// The Hello carries the peer's current key.
+    Duplicate,
+
+    /// The Hello carries a key that was received before.
+    Replayed,
+
+    /// The keys were replaced recently, so the Hello waits for REKEY_INTERVAL.
+    Deferred,
+
+    /// The keys should be exchanged now.
+    Exchange,
+}
+
+struct SecurePeer {
+    local: Ephemeral,
+
+    /// The public key the peer sent us, once received.
+    remote: Option<PublicKey>,
+
+    /// Established once the peer's public key is known.
+    key: Option<Key>,
+
+    /// Distinguishes the nonces of both directions, which share a key.
+    direction: u8,
+
+    /// Counter of the next packet we send.
+    send_counter: u64,
+
+    /// Counter of the last packet we received, used to reject replays.
+    recv_counter: Option<u64>,
+
+    /// When the key exchange last completed.
+    established_at: Option<Duration>,
+
+    /// A Hello with new keys of the peer, waiting for REKEY_INTERVAL to elapse.
+    deferred: Option<Hello>,
+
+    /// Packets waiting for the key exchange to complete.
+    queued: Vec<Vec<u8>>,
+}
+
+impl SecurePeer {
+    fn new() -> Self {
+        Self {
+            local: Ephemeral::new(),
+            remote: None,
+            key: None,
+            direction: 0,
+            send_counter: 0,
+            recv_counter: None,
+            established_at: None,
+            deferred: None,
+            queued: Vec::new(),
+        }
+    }
+
+    /// Our Hello, answering the public key if there is one.
+    fn hello(&mut self, psk: Option<&[u8; 32]>, echo: Option<&PublicKey>) -> Vec<u8> {
+        let echo = echo.map_or([0; 32], |echo| echo.to_bytes());
+        self.local.sent = true;
+
+        let mut packet = Vec::with_capacity(1 + HELLO_LEN);
+        packet.push(HELLO);
+        packet.extend_from_slice(self.local.public.as_bytes());
+        packet.extend_from_slice(&self.local.nonce);
+        packet.extend_from_slice(&echo);
+        packet.extend_from_slice(&hello_tag(psk, &packet[1..]));
+        packet
+    }
+
+    /// Whether the peer may replace its keys now.
+    fn may_rekey(&self, now: Duration) -> bool {
+        self.established_at.is_none_or(|at| now.saturating_sub(at) >= REKEY_INTERVAL)
+    }
+
+    fn nonce(direction: u8, counter: u64) -> Nonce {
+        let mut nonce = [0u8; 12];
+        nonce[0] = direction;
+        nonce[4..].copy_from_slice(&counter.to_be_bytes());
+        nonce.into()
+    }
+
+    /// Decide what to do with a Hello, given the public keys used before.
+    fn receive(&mut self, hello: Hello, seen: &HashSet<[u8; 32]>, now: Duration) -> HelloAction {
+        if self.remote == Some(hello.public) {
+            self.deferred = None;
+            return HelloAction::Duplicate;
+        }
+        if seen.contains(hello.public.as_bytes()) || hello.public == self.local.public {
+            return HelloAction::Replayed;
+        }
+        if !self.may_rekey(now) {
+            self.deferred = Some(hello);
+            return HelloAction::Deferred;
+        }
+        HelloAction::Exchange
+    }
+
+    /// The deferred Hello, once REKEY_INTERVAL elapsed.
+    fn take_deferred(&mut self, now: Duration) -> Option<Hello> {
+        match self.may_rekey(now) {
+            true => self.deferred.take(),
+            false => None,
+        }
+    }
+
+    /// Derive the session key from the Hello of the peer.
+    /// Returns the Hello to answer it with, unless it answered ours.
+    fn exchange(&mut self, hello: Hello, psk: Option<&[u8; 32]>, seen: &mut HashSet<[u8; 32]>, now: Duration) -> Option<Vec<u8>> {
+        seen.insert(hello.public.to_bytes());
+
+        // the peer already derived the key from ours, otherwise it needs fresh keys of ours.
+        let answered = hello.echo == self.local.public.to_bytes();
+        if !answered && self.local.used {
+            self.local = Ephemeral::new();
+        }
+
+        // our own keys are never accepted either, should our Hellos be reflected.
+        seen.insert(self.local.public.to_bytes());
+        self.establish(hello, psk, now);
+        (!answered).then(|| self.hello(psk, Some(&hello.public)))
+    }
+
+    fn establish(&mut self, hello: Hello, psk: Option<&[u8; 32]>, now: Duration) {
+        let local = &mut self.local;
+        let remote = hello.public;
+        let shared = local.secret.diffie_hellman(&remote);
+        let ((lo, lo_nonce), (hi, hi_nonce)) = if local.public.as_bytes() < remote.as_bytes() {
+            ((local.public, local.nonce), (remote, hello.nonce))
+        } else {
+            ((remote, hello.nonce), (local.public, local.nonce))
+        };
+
+        let key = Sha256::new()
+            .chain_update(KDF_CONTEXT)
+            .chain_update(psk.unwrap_or(&[0; 32]))
+            .chain_update(shared.as_bytes())
+            .chain_update(lo.as_bytes())
+            .chain_update(hi.as_bytes())
+            .chain_update(lo_nonce)
+            .chain_update(hi_nonce)
+            .finalize();
+
+        local.used = true;
+        self.direction = (local.public.as_bytes() > remote.as_bytes()) as u8;
+        self.key = Some(key);
+        self.remote = Some(remote);
+        self.send_counter = 0;
+        self.recv_counter = None;
+        self.established_at = Some(now);
+        self.deferred = None;
+    }
+
+    fn seal(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+        let cipher = ChaCha20Poly1305::new(self.key.as_ref()?);
+        let counter = self.send_counter;
+        self.send_counter += 1;
+
+        let sealed = cipher.encrypt(&Self::nonce(self.direction, counter), data).ok()?;
+        let mut packet = Vec::with_capacity(9 + sealed.len());
+        packet.push(SEALED);
+        packet.extend_from_slice(&counter.to_be_bytes());
+        packet.extend_from_slice(&sealed);
+        Some(packet)
+    }
+
+    fn open(&mut self, packet: &[u8]) -> Option<Vec<u8>> {
+        let cipher = ChaCha20Poly1305::new(self.key.as_ref()?);
+        let counter = u64::from_be_bytes(packet.get(..8)?.try_into().ok()?);
+        if self.recv_counter.is_some_and(|last| counter <= last) {
+            return None;
+        }
+
+        let plain = cipher.decrypt(&Self::nonce(1 - self.direction, counter), &packet[8..]).ok()?;
+        self.recv_counter = Some(counter);
+        Some(plain)
+    }
+}
+
+/// The tag proving the sender of a Hello knows the pre-shared key.
+fn hello_tag(psk: Option<&[u8; 32]>, hello: &[u8]) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(HELLO_CONTEXT)
+        .chain_update(psk.unwrap_or(&[0; 32]))
+        .chain_update(hello)
+        .finalize()
+        .into()
+}
+
+/// The contents of a Hello, if its tag is valid.
+fn verify_hello(hello: &[u8], psk: Option<&[u8; 32]>) -> Option<Hello> {
+    if hello.len() != HELLO_LEN || hello[80..] != hello_tag(psk, &hello[..80]) {
+        return None;
+    }
+
+    Some(Hello {
+        public: PublicKey::from(<[u8; 32]>::try_from(&hello[..32]).ok()?),
+        nonce: hello[32..48].try_into().ok()?,
+        echo: hello[48..80].try_into().ok()?,
+    })
+}
+
+impl SecureChannels {
+    /// Create the SecureChannels. An empty "psk" leaves the key exchange unauthenticated.
+    pub fn new(enabled: bool, psk: &str) -> Self {
+        if enabled && psk.is_empty() {
+            log::warn!("The secure channel has no pre-shared key, so its key exchange is not authenticated.");
+        }
+
+        let psk = (!psk.is_empty()).then(|| Sha256::new()
+            .chain_update(KDF_CONTEXT)
+            .chain_update(psk.as_bytes())
+            .finalize()
+            .into());
+
+        Self {
+            enabled,
+            psk,
+            peers: Mutex::new(HashMap::new()),
+            seen: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Whether packets are encrypted.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Whether the key exchange with the peer has completed.
+    pub fn is_established(&self, user: UserId) -> bool {
+        self.peers.lock().get(&user).is_some_and(|peer| peer.key.is_some())
+    }
+
+    /// The number of bytes added to every packet sent.
+    pub(crate) fn overhead(&self) -> usize {
+        match self.enabled {
+            true => 1 + 8 + 16,
+            false => 0,
+        }
+    }
+
+    /// Send a packet to the user, encrypting it if the channel is enabled.
+    pub(crate) fn send(&self, backend: &Backend, to: UserId, data: &[u8]) {
+        if !self.enabled {
+            backend.send_packet(to, data);
+            return;
+        }
+
+        let mut peers = self.peers.lock();
+        let peer = peers.entry(to).or_insert_with(SecurePeer::new);
+        match peer.seal(data) {
+            Some(packet) => backend.send_packet(to, &packet),
+            None => {
+                peer.queued.push(data.to_vec());
+                if !peer.local.sent {
+                    backend.send_packet(to, &peer.hello(self.psk.as_ref(), None));
+                }
+            }
+        }
+    }
+
+    /// Begin the key exchange with the user if it has not begun yet.
+    pub(crate) fn connect(&self, backend: &Backend, to: UserId) {
+        if !self.enabled {
+            return;
+        }
+
+        let mut peers = self.peers.lock();
+        let peer = peers.entry(to).or_insert_with(SecurePeer::new);
+        if peer.key.is_none() && !peer.local.sent {
+            backend.send_packet(to, &peer.hello(self.psk.as_ref(), None));
+        }
+    }
+
+    /// Complete the key exchange with the peer, and send the packets waiting for it.
+    fn exchange(&self, backend: &Backend, with: UserId, peer: &mut SecurePeer, hello: Hello, now: Duration) {
+        if let Some(answer) = peer.exchange(hello, self.psk.as_ref(), &mut self.seen.lock(), now) {
+            backend.send_packet(with, &answer);
+        }
+
+        for data in std::mem::take(&mut peer.queued) {
+            if let Some(sealed) = peer.seal(&data) {
+                backend.send_packet(with, &sealed);
+            }
+        }
+    }
+
+    /// Apply the key exchanges that were deferred by REKEY_INTERVAL.
+    pub(crate) fn update(&self, backend: &Backend, now: Duration) {
+        if !self.enabled {
+            return;
+        }
+
+        let mut peers = self.peers.lock();
+        for (user, peer) in peers.iter_mut() {
+            let Some(hello) = peer.take_deferred(now) else { continue };
+            if peer.receive(hello, &self.seen.lock(), now) == HelloAction::Exchange {
+                log::debug!("Replacing the keys of UserID '{:?}'", user);
+                self.exchange(backend, *user, peer, hello, now);
+            }
+        }
+    }
+
+    /// Decrypt a received packet. Returns "None" if the packet was part of the
+    /// key exchange, or failed to authenticate and was discarded.
+    pub(crate) fn open<'a>(&self, backend: &Backend, from: UserId, packet: &'a [u8], now: Duration) -> Option<Cow<'a, [u8]>> {
+        if !self.enabled {
+            return Some(Cow::Borrowed(packet));
+        }
+
+        let mut peers = self.peers.lock();
+        let peer = peers.entry(from).or_insert_with(SecurePeer::new);
+        match packet.split_first() {
+            Some((&HELLO, hello)) => {
+                let Some(hello) = verify_hello(hello, self.psk.as_ref()) else {
+                    log::warn!("Discarded a key exchange packet from UserID '{:?}' that failed to authenticate.", from);
+                    return None;
+                };
+
+                let action = peer.receive(hello, &self.seen.lock(), now);
+                match action {
+                    HelloAction::Duplicate => {}
+                    HelloAction::Replayed => {
+                        log::warn!("Discarded a key exchange packet from UserID '{:?}' with keys that were used before.", from);
+                    }
+                    HelloAction::Deferred => {
+                        log::debug!("Deferred replacing the keys of UserID '{:?}', which were replaced recently.", from);
+                    }
+                    HelloAction::Exchange => self.exchange(backend, from, peer, hello, now),
+                }
+                None
+            }
+            Some((&SEALED, sealed)) => {
+                let plain = peer.open(sealed);
+                if plain.is_none() {
+                    log::warn!("Discarded a packet from UserID '{:?}' that failed to authenticate.", from);
+                }
+                plain.map(Cow::Owned)
+            }
+            _ => {
+                log::warn!("Discarded an unencrypted packet from UserID '{:?}'", from);
+                None
+            }
+        }
+    }
+
+    /// Forget the keys of a peer. The public keys used with it are still never accepted again.
+    pub(crate) fn forget(&self, user: UserId) {
+        self.peers.lock().remove(&user);
+    }
+
+    /// Forget the keys of all peers.
+    pub(crate) fn clear(&self) {
+        self.peers.lock().clear();
+    }
+}
+
+/// Begin key exchanges when connecting to a lobby or when a member joins,
+/// apply deferred key exchanges, and forget keys when members leave.
+pub fn update_secure_channels(
+    backend: Res<Backend>,
+    channels: Res<SecureChannels>,
+    router: Res<Router>,
+    time: Res<Time<Real>>,
+    mut on_lobby_join: EventReader<OnLobbyJoin>,
+    mut on_lobby_exit: EventReader<OnLobbyExit>,
+    mut on_lobby_change: EventReader<OnLobbyChange>,
+) {
+    if on_lobby_exit.read().count() > 0 {
+        channels.clear();
+    }
+
+    if on_lobby_join.read().count() > 0 {
+        for member in backend.lobby_members() {
+            if router.is_peer(&backend, member) {
+                channels.connect(&backend, member);
+            }
+        }
+    }
+
+    for ev in on_lobby_change.read() {
+        match ev {
+            OnLobbyChange::Joined(user) => {
+                if router.is_peer(&backend, *user) {
+                    channels.connect(&backend, *user);
+                }
+            }
+            OnLobbyChange::Exited(user)
+            | OnLobbyChange::Kicked { target: user, .. }
+            | OnLobbyChange::Banned { target: user, .. } => channels.forget(*user),
+        }
+    }
+
+    channels.update(&backend, time.elapsed());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PSK: Option<&[u8; 32]> = Some(&[7; 32]);
+
+    /// One side of a channel, with the public keys it used.
+    struct Side {
+        peer: SecurePeer,
+        seen: HashSet<[u8; 32]>,
+        psk: Option<&'static [u8; 32]>,
+    }
+
+    impl Side {
+        fn new(psk: Option<&'static [u8; 32]>) -> Self {
+            Side { peer: SecurePeer::new(), seen: HashSet::new(), psk }
+        }
+
+        /// Receive a Hello, applying any deferred one first, and return the answer.
+        fn deliver(&mut self, hello: &[u8], now: Duration) -> (HelloAction, Option<Vec<u8>>) {
+            let mut answer = None;
+            if let Some(deferred) = self.peer.take_deferred(now)
+                && self.peer.receive(deferred, &self.seen, now) == HelloAction::Exchange
+            {
+                answer = self.peer.exchange(deferred, self.psk, &mut self.seen, now);
+            }
+
+            let hello = verify_hello(&hello[1..], self.psk).unwrap();
+            let action = self.peer.receive(hello, &self.seen, now);
+            if action == HelloAction::Exchange {
+                answer = self.peer.exchange(hello, self.psk, &mut self.seen, now);
+            }
+            (action, answer)
+        }
+
+        /// Seal a packet, checking that its key and nonce were never used before.
+        fn seal(&mut self, data: &[u8], used: &mut HashSet<(Key, u8, u64)>) -> Vec<u8> {
+            let nonce = (self.peer.key.unwrap(), self.peer.direction, self.peer.send_counter);
+            assert!(used.insert(nonce), "a key and nonce were reused");
+            self.peer.seal(data).unwrap()
+        }
+    }
+
+    /// Two sides that completed the key exchange.
+    fn connect(a: &mut Side, b: &mut Side, now: Duration) {
+        let hello = a.peer.hello(a.psk, None);
+        let (_, answer) = b.deliver(&hello, now);
+        let (action, answer) = a.deliver(&answer.unwrap(), now);
+        assert_eq!((action, answer), (HelloAction::Exchange, None));
+    }
+
+    fn pair(a_psk: Option<&'static [u8; 32]>, b_psk: Option<&'static [u8; 32]>) -> (SecurePeer, SecurePeer) {
+        let (mut a, mut b) = (Side::new(a_psk), Side::new(b_psk));
+        let hello = a.peer.hello(a_psk, None);
+        let answer = b.peer.hello(b_psk, Some(&a.peer.local.public));
+        let now = Duration::ZERO;
+        b.peer.exchange(verify_hello(&hello[1..], a_psk).unwrap(), b_psk, &mut b.seen, now);
+        a.peer.exchange(verify_hello(&answer[1..], b_psk).unwrap(), a_psk, &mut a.seen, now);
+        (a.peer, b.peer)
+    }
+
+    /// Whether packets sent by one side are opened by the other.
+    fn communicates(a: &mut Side, b: &mut Side, used: &mut HashSet<(Key, u8, u64)>) -> bool {
+        let sealed = a.seal(b"ping", used);
+        let reply = b.seal(b"pong", used);
+        b.peer.open(&sealed[1..]).is_some() && a.peer.open(&reply[1..]).is_some()
+    }
+
+    #[test]
+    fn seal_and_open() {
+        let (mut a, mut b) = pair(PSK, PSK);
+        for data in [&b"first"[..], b"second", b""] {
+            let sealed = a.seal(data).unwrap();
+            assert_eq!(sealed[0], SEALED);
+            assert_eq!(b.open(&sealed[1..]).as_deref(), Some(data));
+        }
+
+        let reply = b.seal(b"reply").unwrap();
+        assert_eq!(a.open(&reply[1..]).as_deref(), Some(&b"reply"[..]));
+    }
+
+    #[test]
+    fn tampered_packets_are_rejected() {
+        let (mut a, mut b) = pair(PSK, PSK);
+        let sealed = a.seal(b"payload").unwrap();
+        for i in 1..sealed.len() {
+            let mut tampered = sealed.clone();
+            tampered[i] ^= 1;
+            assert!(b.open(&tampered[1..]).is_none(), "byte {i} was not authenticated");
+        }
+        assert!(b.open(&sealed[1..sealed.len() - 1]).is_none());
+        assert!(b.open(&sealed[1..]).is_some());
+    }
+
+    #[test]
+    fn replayed_packets_are_rejected() {
+        let (mut a, mut b) = pair(PSK, PSK);
+        let first = a.seal(b"first").unwrap();
+        let second = a.seal(b"second").unwrap();
+        assert!(b.open(&second[1..]).is_some());
+        assert!(b.open(&second[1..]).is_none());
+        assert!(b.open(&first[1..]).is_none());
+    }
+
+    #[test]
+    fn packets_of_a_reflected_direction_are_rejected() {
+        let (mut a, _) = pair(PSK, PSK);
+        let sealed = a.seal(b"payload").unwrap();
+        assert!(a.open(&sealed[1..]).is_none());
+    }
+
+    #[test]
+    fn different_pre_shared_keys_do_not_communicate() {
+        let (mut a, mut b) = pair(PSK, Some(&[8; 32]));
+        let sealed = a.seal(b"payload").unwrap();
+        assert!(b.open(&sealed[1..]).is_none());
+    }
+
+    #[test]
+    fn hellos_are_authenticated() {
+        let mut peer = SecurePeer::new();
+        let hello = peer.hello(PSK, None);
+        assert_eq!(hello[0], HELLO);
+        assert_eq!(hello.len(), 1 + HELLO_LEN);
+        assert_eq!(verify_hello(&hello[1..], PSK).map(|h| h.public), Some(peer.local.public));
+        assert!(verify_hello(&hello[1..], Some(&[8; 32])).is_none());
+        assert!(verify_hello(&hello[1..], None).is_none());
+        assert!(verify_hello(&hello[1..33], PSK).is_none());
+
+        // a man in the middle cannot substitute its own key, nonce or echo.
+        let attacker = SecurePeer::new();
+        for (range, bytes) in [(1..33, attacker.local.public.to_bytes().to_vec()), (33..49, vec![0; 16]), (49..81, vec![1; 32])] {
+            let mut forged = hello.clone();
+            forged[range].copy_from_slice(&bytes);
+            assert!(verify_hello(&forged[1..], PSK).is_none());
+        }
+    }
+
+    #[test]
+    fn simultaneous_hellos_agree_on_a_key() {
+        let (mut a, mut b) = (Side::new(PSK), Side::new(PSK));
+        let a_hello = a.peer.hello(PSK, None);
+        let b_hello = b.peer.hello(PSK, None);
+        let (_, a_answer) = a.deliver(&b_hello, Duration::ZERO);
+        let (_, b_answer) = b.deliver(&a_hello, Duration::ZERO);
+
+        // the answers carry the keys that were already received.
+        assert_eq!(a.deliver(&b_answer.unwrap(), Duration::ZERO).0, HelloAction::Duplicate);
+        assert_eq!(b.deliver(&a_answer.unwrap(), Duration::ZERO).0, HelloAction::Duplicate);
+        assert!(communicates(&mut a, &mut b, &mut HashSet::new()));
+    }
+
+    #[test]
+    fn rekeys_are_rate_limited() {
+        let (a, _) = pair(PSK, PSK);
+        assert!(!a.may_rekey(Duration::ZERO));
+        assert!(!a.may_rekey(REKEY_INTERVAL / 2));
+        assert!(a.may_rekey(REKEY_INTERVAL));
+        assert!(SecurePeer::new().may_rekey(Duration::ZERO));
+    }
+
+    #[test]
+    fn rekeys_use_fresh_keys() {
+        let (mut a, mut b) = (Side::new(PSK), Side::new(PSK));
+        connect(&mut a, &mut b, Duration::ZERO);
+        let first = a.peer.local.public;
+
+        // B restarts, and A answers its new keys with fresh keys of its own.
+        b = Side::new(PSK);
+        connect(&mut b, &mut a, REKEY_INTERVAL);
+        assert_ne!(a.peer.local.public, first);
+        assert!(communicates(&mut a, &mut b, &mut HashSet::new()));
+    }
+
+    #[test]
+    fn replayed_hellos_never_reuse_a_key_and_nonce() {
+        let mut used = HashSet::new();
+        let mut hellos = Vec::new();
+        let (mut a, mut b) = (Side::new(PSK), Side::new(PSK));
+
+        // the first session.
+        let hello = a.peer.hello(PSK, None);
+        let (_, answer) = b.deliver(&hello, Duration::ZERO);
+        let answer = answer.unwrap();
+        a.deliver(&answer, Duration::ZERO);
+        hellos.extend([hello, answer]);
+        for _ in 0..3 {
+            assert!(communicates(&mut a, &mut b, &mut used));
+        }
+
+        // B restarts, starting the second session.
+        b = Side::new(PSK);
+        let hello = b.peer.hello(PSK, None);
+        let (_, answer) = a.deliver(&hello, REKEY_INTERVAL);
+        let answer = answer.unwrap();
+        b.deliver(&answer, REKEY_INTERVAL);
+        hellos.extend([hello, answer]);
+        assert!(communicates(&mut a, &mut b, &mut used));
+
+        // every old Hello replayed to A, long after the rekey.
+        let now = REKEY_INTERVAL * 3;
+        for hello in &hellos {
+            let (action, answer) = a.deliver(hello, now);
+            assert!(matches!(action, HelloAction::Duplicate | HelloAction::Replayed), "{action:?}");
+            assert!(answer.is_none());
+        }
+        assert!(communicates(&mut a, &mut b, &mut used));
+
+        // the restarted B has not seen A's first keys, so replaying them makes it exchange
+        // fresh keys, and the two converge on a new key once their answers arrive.
+        let mut now = now;
+        let mut to_a = b.deliver(&hellos[0], now).1.into_iter().collect::<Vec<_>>();
+        let mut to_b = Vec::new();
+        for _ in 0..8 {
+            now += REKEY_INTERVAL;
+            for hello in std::mem::take(&mut to_a) {
+                to_b.extend(a.deliver(&hello, now).1);
+            }
+            for hello in std::mem::take(&mut to_b) {
+                to_a.extend(b.deliver(&hello, now).1);
+            }
+        }
+        assert!(a.peer.deferred.is_none() && b.peer.deferred.is_none());
+        assert!(communicates(&mut a, &mut b, &mut used));
+    }
+}
@@ -0,0 +1,248 @@
+//! Policies for accepting or rejecting incoming P2P session requests.
+//!
+//! A backend reports every remote user that attempts to open a session with us.
+//! Nothing is delivered to the MessageRegistry from that user until the request
+//! has been accepted according to the configured SessionPolicy.
+
+use std::time::Duration;
+use bevy::prelude::*;
+use bevy::log;
+use serde::Deserialize;
+use crate::backends::{Backend, IBackend, IBackendEvents, UserId};
+use crate::context::NetContext;
+use crate::NetMode;
+
+/// Which remote users are allowed to open a P2P session with this user.
+/// A dedicated server rejects every request while it has "server.max_clients" clients.
+#[derive(Deserialize, Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub enum SessionPolicy {
+    /// Accept requests from anyone.
+    Anyone,
+
+    /// Accept requests only from members of the lobby the user is currently in.
+    /// A dedicated server has no lobby to check against, so it accepts anyone.
+    #[default]
+    LobbyMembers,
+
+    /// Accept requests only from users on the friends list.
+    /// A dedicated server has no friends list, so LobbyMembers is used instead.
+    Friends,
+
+    /// Accept requests for which the SessionFilter returns "true".
+    /// Requests are rejected if no filter was provided.
+    Custom,
+}
+
+type FilterFn = dyn Fn(UserId, &Backend) -> bool + Send + Sync;
+
+/// User-supplied filter used when the SessionPolicy is Custom.
+/// Set with "SkynetAppExt::set_session_filter".
+#[derive(Resource)]
+pub struct SessionFilter(pub(crate) Box<FilterFn>);
+
+/// A P2P session request was rejected.
+#[derive(Event, Debug, Clone)]
+pub struct OnSessionRejected {
+    /// The user that attempted to open a session.
+    pub user: UserId,
+
+    /// Why the request was rejected.
+    pub reason: SessionRejectReason,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum SessionRejectReason {
+    /// The user did not become a member of the current lobby in time.
+    NotLobbyMember,
+
+    /// The user is not on the friends list.
+    NotFriend,
+
+    /// The SessionFilter returned "false".
+    Filtered,
+
+    /// The policy is Custom, but no SessionFilter was provided.
+    NoFilter,
+
+    /// The dedicated server already has "server.max_clients" clients.
+    ServerFull,
+}
+
+impl std::fmt::Display for SessionRejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use SessionRejectReason::*;
+        f.write_str(match *self {
+            NotLobbyMember => "Not a Lobby Member",
+            NotFriend => "Not a Friend",
+            Filtered => "Rejected by Filter",
+            NoFilter => "No Session Filter",
+            ServerFull => "Server Full",
+        })
+    }
+}
+
+/// Session requests that could not be decided yet.
+/// A member's session request may arrive before we see them in the lobby,
+/// so requests that fail the LobbyMembers check are retried until they expire.
+#[derive(Resource, Default)]
+pub struct PendingSessions {
+    requests: Vec<(UserId, Duration)>,
+}
+
+impl PendingSessions {
+    /// Add a request that expires at the given time, unless the user has one pending.
+    fn push(&mut self, user: UserId, expires: Duration) {
+        if !self.requests.iter().any(|(id, _)| *id == user) {
+            self.requests.push((user, expires));
+        }
+    }
+
+    /// Decide the pending requests with the policy check, accepting at most "capacity"
+    /// of them if there is a limit. Returns the decided requests, in the order they arrived.
+    fn decide(
+        &mut self,
+        now: Duration,
+        capacity: Option<usize>,
+        mut check: impl FnMut(UserId) -> Result<(), SessionRejectReason>,
+    ) -> Vec<(UserId, Result<(), SessionRejectReason>)> {
+        let mut accepted = 0;
+        let mut decided = Vec::new();
+        self.requests.retain(|&(user, expires)| {
+            let verdict = match capacity {
+                Some(capacity) if accepted >= capacity => Err(SessionRejectReason::ServerFull),
+                _ => check(user),
+            };
+
+            match verdict {
+                // membership may not have propagated yet, try again next frame.
+                Err(SessionRejectReason::NotLobbyMember) if now < expires => true,
+                verdict => {
+                    accepted += verdict.is_ok() as usize;
+                    decided.push((user, verdict));
+                    false
+                }
+            }
+        });
+        decided
+    }
+}
+
+/// Evaluate incoming session requests against the configured policy.
+pub fn handle_session_requests(
+    mode: Res<State<NetMode>>,
+    context: Res<NetContext>,
+    filter: Option<Res<SessionFilter>>,
+    time: Res<Time<Real>>,
+    mut pending: ResMut<PendingSessions>,
+    mut backend: ResMut<Backend>,
+    mut on_rejected: EventWriter<OnSessionRejected>,
+) {
+    let now = time.elapsed();
+    let timeout = Duration::from_secs_f32(context.config.general.session_timeout);
+    let incoming = backend.events().read_session_requests().collect::<Vec<_>>();
+    for user in incoming {
+        pending.push(user, now + timeout);
+    }
+
+    let policy = context.config.general.session_policy;
+    let is_server = *mode.get() == NetMode::Server;
+
+    // requests accepted in this pass count towards "server.max_clients" too.
+    let members = backend.lobby_members();
+    let capacity = is_server.then(|| (context.config.server.max_clients as usize).saturating_sub(members.len()));
+    let decided = pending.decide(now, capacity, |user| match policy {
+        SessionPolicy::Anyone => Ok(()),
+        SessionPolicy::LobbyMembers | SessionPolicy::Friends if is_server => Ok(()),
+        SessionPolicy::LobbyMembers => {
+            if members.contains(&user) {
+                Ok(())
+            } else {
+                Err(SessionRejectReason::NotLobbyMember)
+            }
+        }
+        SessionPolicy::Friends => {
+            if backend.is_friend(user) {
+                Ok(())
+            } else {
+                Err(SessionRejectReason::NotFriend)
+            }
+        }
+        SessionPolicy::Custom => match &filter {
+            None => Err(SessionRejectReason::NoFilter),
+            Some(filter) if (filter.0)(user, &backend) => Ok(()),
+            Some(_) => Err(SessionRejectReason::Filtered),
+        }
+    });
+
+    for (user, verdict) in decided {
+        match verdict {
+            Ok(()) => {
+                log::debug!("Accepted P2P Session Request from UserID: '{:?}'", user);
+                backend.accept_session(user);
+            }
+            Err(reason) => {
+                log::warn!("Rejected P2P Session Request from UserID: '{:?}' (reason: '{reason}')", user);
+                backend.reject_session(user);
+                on_rejected.write(OnSessionRejected { user, reason });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(id: u64) -> UserId {
+        UserId::from_raw(id)
+    }
+
+    fn pending(users: impl IntoIterator<Item = u64>, expires: Duration) -> PendingSessions {
+        let mut pending = PendingSessions::default();
+        for id in users {
+            pending.push(user(id), expires);
+        }
+        pending
+    }
+
+    #[test]
+    fn requests_in_one_frame_respect_max_clients() {
+        let mut pending = pending(1..=4, Duration::ZERO);
+        let decided = pending.decide(Duration::ZERO, Some(3), |_| Ok(()));
+        let verdicts = decided.iter().map(|(_, verdict)| *verdict).collect::<Vec<_>>();
+        assert_eq!(verdicts, [Ok(()), Ok(()), Ok(()), Err(SessionRejectReason::ServerFull)]);
+        assert!(pending.requests.is_empty());
+    }
+
+    #[test]
+    fn rejected_requests_do_not_use_capacity() {
+        let mut pending = pending(1..=3, Duration::ZERO);
+        let decided = pending.decide(Duration::ZERO, Some(1), |user| match user.raw() {
+            1 => Err(SessionRejectReason::Filtered),
+            _ => Ok(()),
+        });
+        let verdicts = decided.iter().map(|(_, verdict)| *verdict).collect::<Vec<_>>();
+        assert_eq!(verdicts, [Err(SessionRejectReason::Filtered), Ok(()), Err(SessionRejectReason::ServerFull)]);
+    }
+
+    #[test]
+    fn non_members_are_retried_until_they_expire() {
+        let expires = Duration::from_secs(1);
+        let mut pending = pending([1, 2], expires);
+        pending.push(user(1), expires * 2);
+        assert_eq!(pending.requests.len(), 2);
+
+        let members = [user(2)];
+        let check = |user| match members.contains(&user) {
+            true => Ok(()),
+            false => Err(SessionRejectReason::NotLobbyMember),
+        };
+        let decided = pending.decide(Duration::ZERO, None, check);
+        assert_eq!(decided, [(user(2), Ok(()))]);
+
+        assert!(pending.decide(expires / 2, None, check).is_empty());
+        let decided = pending.decide(expires, None, check);
+        assert_eq!(decided, [(user(1), Err(SessionRejectReason::NotLobbyMember))]);
+        assert!(pending.requests.is_empty());
+    }
+}
@@ -0,0 +1,965 @@
+//! Transfer of large blobs, such as custom maps, replays or save files.
+//!
+//! Blobs are split into chunks of "transfer.chunk_size" bytes and sent as
+//! internal messages, so transfers work over any backend. The receiver
+//! acknowledges every chunk, and the sender keeps at most "transfer.window"
+//! unacknowledged bytes in flight. Chunks have a low priority, so transfers
+//! use the bandwidth left over by other messages.
+//!
+//! Chunks the receiver dropped, such as when its channel was full, are sent
+//! again from the last acknowledged byte, either when the receiver acknowledges
+//! the same bytes twice, or when no progress was made within a retransmission
+//! timeout derived from the measured round-trip time.
+//!
+//! Blobs sent to a user under the name of a transfer to it that is still in
+//! progress are offered once that transfer ends, since the receiver tells
+//! transfers apart by name.
+//!
+//! The receiver keeps the data of interrupted transfers for "transfer.resume_timeout"
+//! seconds. When the same blob is offered again, it acknowledges the bytes it
+//! already has and the transfer resumes from there. At most "transfer.max_per_user"
+//! transfers from each user are kept, evicting the interrupted transfer heard from
+//! least recently. Completed blobs are verified against the checksum sent in the offer.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+use bevy::prelude::*;
+use bevy::log;
+use serde::{Deserialize, Serialize};
+use xxhash_rust::xxh64::xxh64;
+use crate::backends::{OnLobbyChange, OnLobbyExit, UserId};
+use crate::context::NetContext;
+use crate::params::{NetReceiver, NetSender};
+use crate::TransferConfig;
+
+/// Identifies a transfer, together with the user that sent it.
+pub type TransferId = u64;
+
+/// Internal message offering a blob to a user.
+#[derive(Serialize, Deserialize, TypePath)]
+pub struct TransferOffer {
+    pub id: TransferId,
+    pub name: String,
+    pub size: u64,
+    pub checksum: u64,
+}
+
+/// Internal message carrying a part of a blob.
+#[derive(Serialize, Deserialize, TypePath)]
+pub struct TransferChunk {
+    pub id: TransferId,
+    pub offset: u64,
+    #[serde(with = "serde_bytes")]
+    pub data: Vec<u8>,
+}
+
+/// Internal message acknowledging the number of contiguous bytes received.
+#[derive(Serialize, Deserialize, TypePath)]
+pub struct TransferAck {
+    pub id: TransferId,
+    pub received: u64,
+}
+
+/// Internal message aborting a transfer, sent by either side.
+#[derive(Serialize, Deserialize, TypePath)]
+pub struct TransferCancel {
+    pub id: TransferId,
+    pub reason: TransferError,
+}
+
+/// Whether we are sending or receiving a transfer.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum TransferDirection {
+    Incoming,
+    Outgoing,
+}
+
+/// Why a transfer failed.
+#[derive(Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Debug)]
+pub enum TransferError {
+    /// The received blob did not match the checksum of the offer.
+    ChecksumMismatch,
+
+    /// The blob is larger than the receiver's "transfer.max_size".
+    TooLarge,
+
+    /// The other side did not respond within "transfer.timeout" seconds.
+    TimedOut,
+
+    /// The other side left the lobby.
+    Disconnected,
+
+    /// The transfer was cancelled.
+    Cancelled,
+
+    /// The receiver already has "transfer.max_per_user" transfers in progress from the sender.
+    TooMany,
+}
+
+impl std::fmt::Display for TransferError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use TransferError::*;
+        f.write_str(match *self {
+            ChecksumMismatch => "Checksum Mismatch",
+            TooLarge => "Too Large",
+            TimedOut => "Timed Out",
+            Disconnected => "Disconnected",
+            Cancelled => "Cancelled",
+            TooMany => "Too Many Transfers",
+        })
+    }
+}
+
+/// Part of a transfer was sent or received.
+#[derive(Event, Debug, Clone)]
+pub struct OnTransferProgress {
+    pub id: TransferId,
+
+    /// The user we are sending to or receiving from.
+    pub user: UserId,
+    pub name: String,
+    pub direction: TransferDirection,
+
+    /// The number of bytes that arrived so far.
+    pub transferred: u64,
+    pub size: u64,
+}
+
+/// A transfer completed, and the blob was verified.
+#[derive(Event, Debug, Clone)]
+pub struct OnTransferComplete {
+    pub id: TransferId,
+
+    /// The user we sent to or received from.
+    pub user: UserId,
+    pub name: String,
+    pub direction: TransferDirection,
+    pub data: Arc<[u8]>,
+}
+
+/// A transfer failed. Incoming transfers that did not fail
+/// verification may be resumed by offering the same blob again.
+#[derive(Event, Debug, Clone)]
+pub struct OnTransferFailed {
+    pub id: TransferId,
+
+    /// The user we were sending to or receiving from.
+    pub user: UserId,
+    pub name: String,
+    pub direction: TransferDirection,
+    pub reason: TransferError,
+}
+
+/// The retransmission timeout before the round-trip time is measured.
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+
+/// The bounds of the retransmission timeout.
+const MIN_RTO: Duration = Duration::from_millis(200);
+const MAX_RTO: Duration = Duration::from_secs(8);
+
+struct Outgoing {
+    to: UserId,
+    name: String,
+    data: Arc<[u8]>,
+    checksum: u64,
+
+    /// Whether the receiver acknowledged the offer.
+    accepted: bool,
+    offered: bool,
+    sent: u64,
+    acked: u64,
+
+    /// Whether we already sent again from "acked", after the receiver dropped a chunk.
+    rewound: bool,
+
+    /// The end of every chunk in flight, and when it was sent.
+    in_flight: VecDeque<(u64, Duration)>,
+
+    /// The smoothed round-trip time of chunks, once measured.
+    srtt: Option<Duration>,
+
+    /// How long to wait for progress before sending again from "acked".
+    rto: Duration,
+
+    /// When the receiver last acknowledged new bytes, or we last sent again.
+    last_progress: Duration,
+
+    /// When we last heard from the receiver.
+    last_seen: Duration,
+}
+
+impl Outgoing {
+    fn new(to: UserId, name: String, data: Arc<[u8]>) -> Self {
+        Self {
+            to,
+            name,
+            checksum: xxh64(&data, 0),
+            data,
+            accepted: false,
+            offered: false,
+            sent: 0,
+            acked: 0,
+            rewound: false,
+            in_flight: VecDeque::new(),
+            srtt: None,
+            rto: INITIAL_RTO,
+            last_progress: Duration::ZERO,
+            last_seen: Duration::ZERO,
+        }
+    }
+
+    fn size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    /// Apply an acknowledgement of the number of contiguous bytes the receiver has.
+    fn ack(&mut self, received: u64, now: Duration) {
+        let received = received.min(self.size());
+        self.last_seen = now;
+        if !self.accepted {
+            // the first acknowledgement tells us where to resume from.
+            self.accepted = true;
+            self.sent = received;
+            self.acked = received;
+            self.last_progress = now;
+            return;
+        }
+
+        if received > self.acked {
+            let mut sample = None;
+            while let Some(&(_, sent_at)) = self.in_flight.front().filter(|(end, _)| *end <= received) {
+                sample = Some(now.saturating_sub(sent_at));
+                self.in_flight.pop_front();
+            }
+
+            if let Some(sample) = sample {
+                let srtt = self.srtt.map_or(sample, |srtt| (srtt * 7 + sample) / 8);
+                self.srtt = Some(srtt);
+                self.rto = (srtt * 2).clamp(MIN_RTO, MAX_RTO);
+            }
+            self.acked = received;
+            self.rewound = false;
+            self.last_progress = now;
+        } else if received == self.acked && received < self.sent && !self.rewound {
+            // the receiver dropped a chunk, so send again from where it stopped.
+            self.rewind(now);
+        }
+    }
+
+    fn rewind(&mut self, now: Duration) {
+        self.sent = self.acked;
+        self.in_flight.clear();
+        self.rewound = true;
+        self.last_progress = now;
+    }
+
+    /// The chunks to send within the window. Sends again from "acked" if the
+    /// receiver made no progress within the retransmission timeout.
+    fn poll(&mut self, id: TransferId, now: Duration, config: &TransferConfig) -> Vec<TransferChunk> {
+        if !self.accepted {
+            return Vec::new();
+        }
+
+        if self.acked < self.sent && now.saturating_sub(self.last_progress) >= self.rto {
+            self.rewind(now);
+            self.rto = (self.rto * 2).min(MAX_RTO);
+        }
+
+        // the timeout counts from the first chunk sent after the window was empty.
+        if self.acked == self.sent {
+            self.last_progress = now;
+        }
+
+        let mut chunks = Vec::new();
+        let size = self.size();
+        while self.sent < size && self.sent - self.acked < config.window as u64 {
+            let end = (self.sent + config.chunk_size.max(1) as u64).min(size);
+            chunks.push(TransferChunk {
+                id,
+                offset: self.sent,
+                data: self.data[self.sent as usize..end as usize].to_vec(),
+            });
+            self.in_flight.push_back((end, now));
+            self.sent = end;
+        }
+        chunks
+    }
+}
+
+struct Incoming {
+    id: TransferId,
+    from: UserId,
+    name: String,
+    size: u64,
+    checksum: u64,
+    data: Vec<u8>,
+
+    /// Whether the sender is currently sending, as opposed to interrupted.
+    active: bool,
+    last_seen: Duration,
+}
+
+impl Incoming {
+    /// Append the chunk if it continues the data. Returns whether it did,
+    /// and the number of contiguous bytes received, to acknowledge.
+    fn receive(&mut self, chunk: &TransferChunk, now: Duration) -> (bool, u64) {
+        self.last_seen = now;
+        let received = self.data.len() as u64;
+        if chunk.offset != received || received + chunk.data.len() as u64 > self.size {
+            // out of order after a resumption or a dropped chunk, so tell the sender where to continue.
+            return (false, received);
+        }
+
+        self.data.extend_from_slice(&chunk.data);
+        (true, self.data.len() as u64)
+    }
+
+    fn is_complete(&self) -> bool {
+        self.data.len() as u64 >= self.size
+    }
+
+    /// Whether the received data matches the checksum of the offer.
+    fn verify(&self) -> bool {
+        xxh64(&self.data, 0) == self.checksum
+    }
+}
+
+/// Sends and receives blobs. Progress is reported with the OnTransferProgress,
+/// OnTransferComplete and OnTransferFailed events.
+#[derive(Resource, Default)]
+pub struct NetTransfer {
+    next_id: TransferId,
+    outgoing: HashMap<TransferId, Outgoing>,
+
+    /// Incoming transfers, keyed by sender and name so they can be resumed.
+    incoming: HashMap<(UserId, String), Incoming>,
+
+    /// Transfers cancelled locally, and the users to notify.
+    cancelled: Vec<(UserId, TransferId)>,
+}
+
+impl NetTransfer {
+    /// Send a blob to the user. The name identifies the blob to the receiver,
+    /// and sending the same blob under the same name resumes an interrupted transfer.
+    /// If a transfer to the user under the same name is in progress, the blob
+    /// is offered once that transfer ends.
+    pub fn send_blob(&mut self, to: UserId, name: impl Into<String>, data: impl Into<Arc<[u8]>>) -> TransferId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.outgoing.insert(id, Outgoing::new(to, name.into(), data.into()));
+        id
+    }
+
+    /// Abort an outgoing transfer.
+    pub fn cancel(&mut self, id: TransferId) {
+        if let Some(transfer) = self.outgoing.remove(&id) {
+            self.cancelled.push((transfer.to, id));
+        }
+    }
+
+    /// The bytes acknowledged and the total size of an outgoing transfer.
+    pub fn progress(&self, id: TransferId) -> Option<(u64, u64)> {
+        self.outgoing.get(&id).map(|t| (t.acked, t.data.len() as u64))
+    }
+
+    /// Whether the outgoing transfer is still in progress.
+    pub fn is_sending(&self, id: TransferId) -> bool {
+        self.outgoing.contains_key(&id)
+    }
+
+    /// Outgoing transfers waiting for an earlier transfer to the same user under the same name.
+    fn queued(&self) -> HashSet<TransferId> {
+        self.outgoing.iter()
+            .filter(|(id, t)| !t.offered && self.outgoing.iter().any(|(other, o)| {
+                other < *id && o.to == t.to && o.name == t.name
+            }))
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    fn incoming_mut(&mut self, from: UserId, id: TransferId) -> Option<&mut Incoming> {
+        self.incoming.values_mut().find(|t| t.from == from && t.id == id && t.active)
+    }
+
+    /// Make room for a new transfer from the user, evicting the interrupted transfer
+    /// heard from least recently. Returns "false" if every transfer is in progress.
+    fn make_room(&mut self, from: UserId, max: u32) -> bool {
+        let count = self.incoming.values().filter(|t| t.from == from).count();
+        if max == 0 || count < max as usize {
+            return true;
+        }
+
+        let evicted = self.incoming.iter()
+            .filter(|(_, t)| t.from == from && !t.active)
+            .min_by_key(|(_, t)| t.last_seen)
+            .map(|(key, _)| key.clone());
+        match evicted {
+            Some(key) => {
+                log::debug!("Discarded the interrupted transfer of '{}' from UserID '{:?}' to make room.", key.1, from);
+                self.incoming.remove(&key);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Accept an offer, resuming the transfer of the same name if there is one.
+    /// Returns the number of bytes already received, to acknowledge.
+    fn offer(&mut self, from: UserId, offer: TransferOffer, config: &TransferConfig, now: Duration) -> Result<u64, TransferError> {
+        let key = (from, offer.name.clone());
+        if config.max_size != 0 && offer.size > config.max_size {
+            return Err(TransferError::TooLarge);
+        }
+        if !self.incoming.contains_key(&key) && !self.make_room(from, config.max_per_user) {
+            return Err(TransferError::TooMany);
+        }
+
+        let transfer = self.incoming.entry(key)
+            .and_modify(|t| if t.checksum != offer.checksum || t.size != offer.size {
+                t.data.clear();
+            })
+            .or_insert_with(|| Incoming {
+                id: offer.id,
+                from,
+                name: offer.name,
+                size: offer.size,
+                checksum: offer.checksum,
+                data: Vec::new(),
+                active: true,
+                last_seen: now,
+            });
+
+        transfer.id = offer.id;
+        transfer.size = offer.size;
+        transfer.checksum = offer.checksum;
+        transfer.active = true;
+        transfer.last_seen = now;
+        Ok(transfer.data.len() as u64)
+    }
+}
+
+/// Offer new transfers, send chunks within the window, and time out transfers.
+pub fn send_transfers(
+    context: Res<NetContext>,
+    time: Res<Time<Real>>,
+    mut transfers: ResMut<NetTransfer>,
+    mut offers: NetSender<TransferOffer>,
+    mut chunks: NetSender<TransferChunk>,
+    mut cancels: NetSender<TransferCancel>,
+    mut on_failed: EventWriter<OnTransferFailed>,
+) {
+    let now = time.elapsed();
+    let config = &context.config.transfer;
+    let timeout = Duration::from_secs_f32(config.timeout);
+
+    for (to, id) in std::mem::take(&mut transfers.cancelled) {
+        cancels.send(to, &TransferCancel { id, reason: TransferError::Cancelled });
+    }
+
+    let queued = transfers.queued();
+    let mut timed_out = Vec::new();
+    for (&id, transfer) in transfers.outgoing.iter_mut() {
+        if queued.contains(&id) {
+            continue;
+        }
+
+        if !transfer.offered {
+            transfer.offered = true;
+            transfer.last_seen = now;
+            offers.send(transfer.to, &TransferOffer {
+                id,
+                name: transfer.name.clone(),
+                size: transfer.data.len() as u64,
+                checksum: transfer.checksum,
+            });
+            continue;
+        }
+
+        if now.saturating_sub(transfer.last_seen) > timeout {
+            timed_out.push(id);
+            continue;
+        }
+
+        for chunk in transfer.poll(id, now, config) {
+            chunks.send(transfer.to, &chunk);
+        }
+    }
+
+    for id in timed_out {
+        if let Some(transfer) = transfers.outgoing.remove(&id) {
+            log::warn!("Transfer of '{}' to UserID '{:?}' timed out.", transfer.name, transfer.to);
+            cancels.send(transfer.to, &TransferCancel { id, reason: TransferError::TimedOut });
+            on_failed.write(OnTransferFailed {
+                id,
+                user: transfer.to,
+                name: transfer.name,
+                direction: TransferDirection::Outgoing,
+                reason: TransferError::TimedOut,
+            });
+        }
+    }
+
+    // interrupted transfers are only kept for a while, in case they are resumed.
+    let resume_timeout = Duration::from_secs_f32(config.resume_timeout);
+    transfers.incoming.retain(|_, t| t.active || now.saturating_sub(t.last_seen) <= resume_timeout);
+
+    let stalled = transfers.incoming.values_mut()
+        .filter(|t| t.active && now.saturating_sub(t.last_seen) > timeout);
+    for transfer in stalled {
+        // keep the data, so the transfer can be resumed.
+        transfer.active = false;
+        on_failed.write(OnTransferFailed {
+            id: transfer.id,
+            user: transfer.from,
+            name: transfer.name.clone(),
+            direction: TransferDirection::Incoming,
+            reason: TransferError::TimedOut,
+        });
+    }
+}
+
+/// Receive offers, chunks, acknowledgements and cancellations.
+#[allow(clippy::too_many_arguments)]
+pub fn recv_transfers(
+    context: Res<NetContext>,
+    time: Res<Time<Real>>,
+    mut transfers: ResMut<NetTransfer>,
+    mut offers: NetReceiver<TransferOffer>,
+    mut chunks: NetReceiver<TransferChunk>,
+    mut acks: NetReceiver<TransferAck>,
+    mut cancels: NetReceiver<TransferCancel>,
+    mut ack_sender: NetSender<TransferAck>,
+    mut cancel_sender: NetSender<TransferCancel>,
+    mut on_progress: EventWriter<OnTransferProgress>,
+    mut on_complete: EventWriter<OnTransferComplete>,
+    mut on_failed: EventWriter<OnTransferFailed>,
+) {
+    let now = time.elapsed();
+    let config = &context.config.transfer;
+
+    while let Some(msg) = offers.recv() {
+        let (from, offer) = (msg.sender, msg.payload);
+        let (id, name, size) = (offer.id, offer.name.clone(), offer.size);
+        match transfers.offer(from, offer, config, now) {
+            Ok(received) => ack_sender.send(from, &TransferAck { id, received }),
+            Err(reason) => {
+                log::warn!("Refused transfer of '{}' from UserID '{:?}' (size: '{}', reason: '{reason}')", name, from, size);
+                cancel_sender.send(from, &TransferCancel { id, reason });
+                on_failed.write(OnTransferFailed {
+                    id,
+                    user: from,
+                    name,
+                    direction: TransferDirection::Incoming,
+                    reason,
+                });
+            }
+        }
+    }
+
+    let mut progressed = Vec::new();
+    while let Some(msg) = chunks.recv() {
+        let (from, chunk) = (msg.sender, msg.payload);
+        let Some(transfer) = transfers.incoming_mut(from, chunk.id) else {
+            continue;
+        };
+
+        let (appended, received) = transfer.receive(&chunk, now);
+        ack_sender.send(from, &TransferAck { id: chunk.id, received });
+        if appended && !progressed.contains(&(from, chunk.id)) {
+            progressed.push((from, chunk.id));
+        }
+    }
+
+    for (from, id) in progressed {
+        let Some(transfer) = transfers.incoming_mut(from, id) else { continue };
+        let (transferred, size) = (transfer.data.len() as u64, transfer.size);
+        on_progress.write(OnTransferProgress {
+            id,
+            user: from,
+            name: transfer.name.clone(),
+            direction: TransferDirection::Incoming,
+            transferred,
+            size,
+        });
+
+        if !transfer.is_complete() {
+            continue;
+        }
+
+        let key = (from, transfer.name.clone());
+        let Some(transfer) = transfers.incoming.remove(&key) else { continue };
+        if transfer.verify() {
+            on_complete.write(OnTransferComplete {
+                id,
+                user: from,
+                name: transfer.name,
+                direction: TransferDirection::Incoming,
+                data: transfer.data.into(),
+            });
+        } else {
+            log::warn!("Transfer of '{}' from UserID '{:?}' failed verification.", transfer.name, from);
+            cancel_sender.send(from, &TransferCancel { id, reason: TransferError::ChecksumMismatch });
+            on_failed.write(OnTransferFailed {
+                id,
+                user: from,
+                name: transfer.name,
+                direction: TransferDirection::Incoming,
+                reason: TransferError::ChecksumMismatch,
+            });
+        }
+    }
+
+    let mut acked = Vec::new();
+    while let Some(msg) = acks.recv() {
+        let (from, ack) = (msg.sender, msg.payload);
+        let Some(transfer) = transfers.outgoing.get_mut(&ack.id).filter(|t| t.to == from) else {
+            continue;
+        };
+
+        transfer.ack(ack.received, now);
+        if !acked.contains(&ack.id) {
+            acked.push(ack.id);
+        }
+    }
+
+    for id in acked {
+        let Some(transfer) = transfers.outgoing.get(&id) else { continue };
+        let size = transfer.data.len() as u64;
+        on_progress.write(OnTransferProgress {
+            id,
+            user: transfer.to,
+            name: transfer.name.clone(),
+            direction: TransferDirection::Outgoing,
+            transferred: transfer.acked,
+            size,
+        });
+
+        if transfer.acked == size {
+            let transfer = transfers.outgoing.remove(&id).unwrap();
+            on_complete.write(OnTransferComplete {
+                id,
+                user: transfer.to,
+                name: transfer.name,
+                direction: TransferDirection::Outgoing,
+                data: transfer.data,
+            });
+        }
+    }
+
+    while let Some(msg) = cancels.recv() {
+        let (from, cancel) = (msg.sender, msg.payload);
+        if let Some(transfer) = transfers.outgoing.remove(&cancel.id).filter(|t| t.to == from) {
+            on_failed.write(OnTransferFailed {
+                id: cancel.id,
+                user: from,
+                name: transfer.name,
+                direction: TransferDirection::Outgoing,
+                reason: cancel.reason,
+            });
+        } else if let Some(transfer) = transfers.incoming_mut(from, cancel.id) {
+            transfer.active = false;
+            on_failed.write(OnTransferFailed {
+                id: cancel.id,
+                user: from,
+                name: transfer.name.clone(),
+                direction: TransferDirection::Incoming,
+                reason: cancel.reason,
+            });
+        }
+    }
+}
+
+/// Fail the transfers of users that leave the lobby. The data of incoming
+/// transfers is kept for "transfer.resume_timeout" seconds, so they can be resumed.
+pub fn update_transfers(
+    mut transfers: ResMut<NetTransfer>,
+    mut on_lobby_exit: EventReader<OnLobbyExit>,
+    mut on_lobby_change: EventReader<OnLobbyChange>,
+    mut on_failed: EventWriter<OnTransferFailed>,
+) {
+    let mut left = Vec::new();
+    if on_lobby_exit.read().count() > 0 {
+        left.extend(transfers.outgoing.values().map(|t| t.to));
+        left.extend(transfers.incoming.values().filter(|t| t.active).map(|t| t.from));
+    }
+
+    for ev in on_lobby_change.read() {
+        match ev {
+            OnLobbyChange::Joined(_) => {}
+            OnLobbyChange::Exited(user)
+            | OnLobbyChange::Kicked { target: user, .. }
+            | OnLobbyChange::Banned { target: user, .. } => left.push(*user),
+        }
+    }
+
+    for user in left {
+        let ids = transfers.outgoing.iter()
+            .filter(|(_, t)| t.to == user)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in ids {
+            let transfer = transfers.outgoing.remove(&id).unwrap();
+            on_failed.write(OnTransferFailed {
+                id,
+                user,
+                name: transfer.name,
+                direction: TransferDirection::Outgoing,
+                reason: TransferError::Disconnected,
+            });
+        }
+
+        for transfer in transfers.incoming.values_mut().filter(|t| t.from == user && t.active) {
+            transfer.active = false;
+            on_failed.write(OnTransferFailed {
+                id: transfer.id,
+                user,
+                name: transfer.name.clone(),
+                direction: TransferDirection::Incoming,
+                reason: TransferError::Disconnected,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    fn user(id: u64) -> UserId {
+        UserId::from_raw(id)
+    }
+
+    fn config() -> TransferConfig {
+        TransferConfig { chunk_size: 10, window: 30, max_per_user: 2, ..default() }
+    }
+
+    fn blob(len: usize) -> Arc<[u8]> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    fn offer(transfers: &NetTransfer, id: TransferId) -> TransferOffer {
+        let transfer = &transfers.outgoing[&id];
+        TransferOffer { id, name: transfer.name.clone(), size: transfer.size(), checksum: transfer.checksum }
+    }
+
+    fn offsets(chunks: &[TransferChunk]) -> Vec<u64> {
+        chunks.iter().map(|chunk| chunk.offset).collect()
+    }
+
+    #[test]
+    fn chunks_are_sent_within_the_window() {
+        let config = config();
+        let mut sender = NetTransfer::default();
+        let id = sender.send_blob(user(2), "map", blob(45));
+        let transfer = sender.outgoing.get_mut(&id).unwrap();
+        assert!(transfer.poll(id, Duration::ZERO, &config).is_empty(), "sent before the offer was accepted");
+
+        transfer.ack(0, Duration::ZERO);
+        assert_eq!(offsets(&transfer.poll(id, Duration::ZERO, &config)), [0, 10, 20]);
+        assert!(transfer.poll(id, MS, &config).is_empty());
+
+        transfer.ack(20, 10 * MS);
+        assert_eq!(offsets(&transfer.poll(id, 10 * MS, &config)), [30, 40]);
+        assert_eq!(transfer.srtt, Some(10 * MS));
+        transfer.ack(45, 20 * MS);
+        assert_eq!(sender.progress(id), Some((45, 45)));
+    }
+
+    #[test]
+    fn duplicate_acks_rewind_once() {
+        let config = config();
+        let mut transfer = Outgoing::new(user(2), "map".into(), blob(100));
+        transfer.ack(0, Duration::ZERO);
+        transfer.poll(0, Duration::ZERO, &config);
+
+        // the chunk at 10 was dropped, so the receiver acknowledges 10 again for the chunk at 20.
+        transfer.ack(10, MS);
+        transfer.ack(10, MS);
+        assert_eq!(offsets(&transfer.poll(0, MS, &config)), [10, 20, 30]);
+        transfer.ack(10, 2 * MS);
+        assert!(transfer.poll(0, 2 * MS, &config).is_empty(), "rewound twice without progress");
+
+        transfer.ack(20, 3 * MS);
+        transfer.ack(20, 3 * MS);
+        assert_eq!(offsets(&transfer.poll(0, 3 * MS, &config)), [20, 30, 40]);
+    }
+
+    #[test]
+    fn stalled_transfers_are_sent_again_after_the_timeout() {
+        let config = config();
+        let mut transfer = Outgoing::new(user(2), "map".into(), blob(100));
+        transfer.ack(0, Duration::ZERO);
+        transfer.poll(0, Duration::ZERO, &config);
+        transfer.ack(10, 50 * MS);
+        assert_eq!(transfer.rto, MIN_RTO);
+
+        // every chunk after the first was dropped, and no acknowledgement follows.
+        let chunks = transfer.poll(0, 50 * MS, &config);
+        assert_eq!(offsets(&chunks), [30]);
+        assert!(transfer.poll(0, 50 * MS + MIN_RTO / 2, &config).is_empty());
+        assert_eq!(offsets(&transfer.poll(0, 50 * MS + MIN_RTO, &config)), [10, 20, 30]);
+
+        // the timeout backs off until progress is made.
+        assert_eq!(transfer.rto, MIN_RTO * 2);
+        assert!(transfer.poll(0, 50 * MS + MIN_RTO * 2, &config).is_empty());
+        assert_eq!(offsets(&transfer.poll(0, 50 * MS + MIN_RTO * 3, &config)), [10, 20, 30]);
+    }
+
+    #[test]
+    fn idle_windows_do_not_trigger_the_timeout() {
+        let config = TransferConfig { window: 10, ..config() };
+        let mut transfer = Outgoing::new(user(2), "map".into(), blob(20));
+        transfer.ack(0, Duration::ZERO);
+        transfer.poll(0, Duration::ZERO, &config);
+        transfer.ack(10, MS);
+
+        let later = MAX_RTO * 2;
+        assert_eq!(offsets(&transfer.poll(0, later, &config)), [10]);
+        assert!(transfer.poll(0, later + MS, &config).is_empty());
+    }
+
+    #[test]
+    fn interrupted_transfers_resume() {
+        let config = config();
+        let mut sender = NetTransfer::default();
+        let mut receiver = NetTransfer::default();
+        let data = blob(45);
+        let first = sender.send_blob(user(2), "map", data.clone());
+        let received = receiver.offer(user(1), offer(&sender, first), &config, Duration::ZERO).unwrap();
+        assert_eq!(received, 0);
+
+        let transfer = sender.outgoing.get_mut(&first).unwrap();
+        transfer.ack(received, Duration::ZERO);
+        for chunk in transfer.poll(first, Duration::ZERO, &config).iter().take(2) {
+            receiver.incoming_mut(user(1), first).unwrap().receive(chunk, Duration::ZERO);
+        }
+
+        // the sender restarts and offers the same blob under a new ID.
+        let mut sender = NetTransfer { next_id: 7, ..default() };
+        let second = sender.send_blob(user(2), "map", data.clone());
+        let received = receiver.offer(user(1), offer(&sender, second), &config, MS).unwrap();
+        assert_eq!(received, 20);
+
+        let transfer = sender.outgoing.get_mut(&second).unwrap();
+        transfer.ack(received, MS);
+        let chunks = transfer.poll(second, MS, &config);
+        assert_eq!(offsets(&chunks), [20, 30, 40]);
+
+        let incoming = receiver.incoming_mut(user(1), second).unwrap();
+        for chunk in &chunks {
+            assert!(incoming.receive(chunk, MS).0);
+        }
+        assert!(incoming.is_complete() && incoming.verify());
+        assert_eq!(incoming.data, &*data);
+    }
+
+    #[test]
+    fn changed_blobs_restart_from_the_beginning() {
+        let config = config();
+        let mut receiver = NetTransfer::default();
+        let offer = |id, checksum| TransferOffer { id, name: "save".into(), size: 30, checksum };
+        receiver.offer(user(1), offer(0, 1), &config, Duration::ZERO).unwrap();
+        let chunk = TransferChunk { id: 0, offset: 0, data: vec![0; 10] };
+        assert_eq!(receiver.incoming_mut(user(1), 0).unwrap().receive(&chunk, Duration::ZERO), (true, 10));
+
+        assert_eq!(receiver.offer(user(1), offer(1, 1), &config, MS), Ok(10));
+        assert_eq!(receiver.offer(user(1), offer(2, 2), &config, MS), Ok(0));
+    }
+
+    #[test]
+    fn out_of_order_chunks_are_not_appended() {
+        let config = config();
+        let mut receiver = NetTransfer::default();
+        let offer = TransferOffer { id: 0, name: "save".into(), size: 15, checksum: 0 };
+        receiver.offer(user(1), offer, &config, Duration::ZERO).unwrap();
+
+        let incoming = receiver.incoming_mut(user(1), 0).unwrap();
+        let chunk = |offset, len| TransferChunk { id: 0, offset, data: vec![0; len] };
+        assert_eq!(incoming.receive(&chunk(10, 5), Duration::ZERO), (false, 0));
+        assert_eq!(incoming.receive(&chunk(0, 10), Duration::ZERO), (true, 10));
+        assert_eq!(incoming.receive(&chunk(0, 10), Duration::ZERO), (false, 10));
+        assert_eq!(incoming.receive(&chunk(10, 10), Duration::ZERO), (false, 10), "past the offered size");
+    }
+
+    #[test]
+    fn corrupted_blobs_fail_verification() {
+        let config = config();
+        let mut sender = NetTransfer::default();
+        let mut receiver = NetTransfer::default();
+        let id = sender.send_blob(user(2), "replay", blob(10));
+        receiver.offer(user(1), offer(&sender, id), &config, Duration::ZERO).unwrap();
+
+        let incoming = receiver.incoming_mut(user(1), id).unwrap();
+        incoming.receive(&TransferChunk { id, offset: 0, data: vec![0xff; 10] }, Duration::ZERO);
+        assert!(incoming.is_complete());
+        assert!(!incoming.verify());
+    }
+
+    #[test]
+    fn offers_are_limited_per_user() {
+        let config = config();
+        let mut receiver = NetTransfer::default();
+        let offer = |id: TransferId, size| TransferOffer { id, name: format!("blob{id}"), size, checksum: 0 };
+        assert_eq!(receiver.offer(user(1), offer(0, config.max_size + 1), &config, Duration::ZERO), Err(TransferError::TooLarge));
+
+        receiver.offer(user(1), offer(1, 10), &config, Duration::ZERO).unwrap();
+        receiver.offer(user(1), offer(2, 10), &config, MS).unwrap();
+        assert_eq!(receiver.offer(user(1), offer(3, 10), &config, 2 * MS), Err(TransferError::TooMany));
+
+        // other users have their own limit.
+        assert!(receiver.offer(user(3), offer(3, 10), &config, 2 * MS).is_ok());
+    }
+
+    #[test]
+    fn interrupted_transfers_are_evicted_least_recent_first() {
+        let config = config();
+        let mut receiver = NetTransfer::default();
+        let offer = |id: TransferId| TransferOffer { id, name: format!("blob{id}"), size: 10, checksum: 0 };
+        receiver.offer(user(1), offer(1), &config, Duration::ZERO).unwrap();
+        receiver.offer(user(1), offer(2), &config, MS).unwrap();
+        for transfer in receiver.incoming.values_mut() {
+            transfer.active = false;
+        }
+
+        assert!(receiver.offer(user(1), offer(3), &config, 2 * MS).is_ok());
+        let mut names = receiver.incoming.keys().map(|(_, name)| name.as_str()).collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["blob2", "blob3"]);
+    }
+
+    #[test]
+    fn cancelled_transfers_notify_the_receiver() {
+        let mut sender = NetTransfer::default();
+        let id = sender.send_blob(user(2), "map", blob(10));
+        assert!(sender.is_sending(id));
+
+        sender.cancel(id);
+        assert!(!sender.is_sending(id));
+        assert_eq!(sender.cancelled, [(user(2), id)]);
+
+        // cancelling again, or an unknown transfer, sends nothing.
+        sender.cancel(id);
+        sender.cancel(id + 1);
+        assert_eq!(sender.cancelled.len(), 1);
+    }
+
+    #[test]
+    fn blobs_with_the_name_of_a_transfer_in_progress_are_queued() {
+        let mut sender = NetTransfer::default();
+        let first = sender.send_blob(user(2), "map", blob(10));
+        let second = sender.send_blob(user(2), "map", blob(20));
+        let other_user = sender.send_blob(user(3), "map", blob(10));
+        let other_name = sender.send_blob(user(2), "save", blob(10));
+        assert_eq!(sender.queued(), HashSet::from([second]));
+
+        sender.outgoing.get_mut(&first).unwrap().offered = true;
+        assert_eq!(sender.queued(), HashSet::from([second]));
+
+        sender.outgoing.remove(&first);
+        assert!(sender.queued().is_empty());
+        assert!(sender.is_sending(other_user) && sender.is_sending(other_name));
+    }
+}
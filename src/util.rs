@@ -39,3 +39,95 @@ impl<'r, T> Iterator for &'r mut Receiver<T> {
         self.recv()
     }
 }
+
+/// Refills at "rate" tokens per second, holding up to "burst" seconds worth.
+/// A rate of zero is unlimited.
+pub(crate) struct TokenBucket {
+    tokens: f32,
+    capacity: f32,
+    rate: f32,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(rate: u32, burst: f32) -> Self {
+        let capacity = (rate as f32 * burst).max(1.0);
+        Self {
+            tokens: capacity,
+            capacity,
+            rate: rate as f32,
+        }
+    }
+
+    pub(crate) fn refill(&mut self, elapsed: f32) {
+        self.tokens = (self.tokens + self.rate * elapsed).min(self.capacity);
+    }
+
+    /// A full bucket admits a single cost larger than its capacity,
+    /// which is then paid back before anything else is admitted.
+    pub(crate) fn can_take(&self, cost: f32) -> bool {
+        self.rate == 0.0 || self.tokens >= cost.min(self.capacity)
+    }
+
+    pub(crate) fn take(&mut self, cost: f32) {
+        if self.rate != 0.0 {
+            self.tokens -= cost;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_starts_full() {
+        let mut bucket = TokenBucket::new(100, 2.0);
+        assert!(bucket.can_take(200.0));
+        bucket.take(1.0);
+        assert!(!bucket.can_take(200.0));
+        assert!(bucket.can_take(199.0));
+    }
+
+    #[test]
+    fn bucket_refills_at_rate() {
+        let mut bucket = TokenBucket::new(100, 1.0);
+        bucket.take(100.0);
+        assert!(!bucket.can_take(1.0));
+
+        bucket.refill(0.25);
+        assert!(bucket.can_take(25.0));
+        assert!(!bucket.can_take(26.0));
+
+        bucket.refill(0.5);
+        assert!(bucket.can_take(75.0));
+    }
+
+    #[test]
+    fn bucket_refill_is_capped() {
+        let mut bucket = TokenBucket::new(100, 1.0);
+        bucket.take(50.0);
+        bucket.refill(60.0);
+        bucket.take(100.0);
+        assert!(!bucket.can_take(1.0));
+    }
+
+    #[test]
+    fn oversized_cost_is_paid_back() {
+        let mut bucket = TokenBucket::new(100, 1.0);
+        assert!(bucket.can_take(250.0));
+        bucket.take(250.0);
+        assert!(!bucket.can_take(1.0));
+
+        bucket.refill(1.5);
+        assert!(!bucket.can_take(1.0));
+        bucket.refill(0.01);
+        assert!(bucket.can_take(1.0));
+    }
+
+    #[test]
+    fn zero_rate_is_unlimited() {
+        let mut bucket = TokenBucket::new(0, 1.0);
+        bucket.take(1e9);
+        assert!(bucket.can_take(1e9));
+    }
+}